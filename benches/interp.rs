@@ -0,0 +1,166 @@
+use lib_stacked::{CodeGen, Inst, Jump, StackOp, ExprKind};
+use lib_stacked::exec::Machine;
+use lib_stacked::loader::Program;
+
+use std::fs::File;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+const THREADS: usize = 8;
+
+fn fib(n: u32) -> Vec<Inst> {
+    vec![
+        Inst::StackOp(StackOp::Push(n)),
+        Inst::Call(0),
+        Inst::StackOp(StackOp::Pop),
+        Inst::Halt,
+
+        Inst::Label(0),
+        Inst::StackOp(StackOp::Dup),
+        Inst::StackOp(StackOp::Push(2)),
+        Inst::StackOp(StackOp::Cmp),
+        Inst::Jump(Jump::Lesser, 1),
+        Inst::StackOp(StackOp::Dup),
+        Inst::StackOp(StackOp::Push(1)),
+        Inst::BinaryExpr(ExprKind::Sub),
+        Inst::Call(0),
+        Inst::StackOp(StackOp::Swap),
+        Inst::StackOp(StackOp::Push(2)),
+        Inst::BinaryExpr(ExprKind::Sub),
+        Inst::Call(0),
+        Inst::BinaryExpr(ExprKind::Add),
+        Inst::Return,
+
+        Inst::Label(1),
+        Inst::Return,
+    ]
+}
+
+fn count(n: u32) -> Vec<Inst> {
+    vec![
+        Inst::StackOp(StackOp::Push(0)),
+
+        Inst::Label(0),
+        Inst::StackOp(StackOp::Push(1)),
+        Inst::BinaryExpr(ExprKind::Add),
+        Inst::StackOp(StackOp::Dup),
+        Inst::StackOp(StackOp::Push(n)),
+        Inst::StackOp(StackOp::Cmp),
+        Inst::Jump(Jump::Lesser, 0),
+
+        Inst::StackOp(StackOp::Pop),
+        Inst::Halt,
+    ]
+}
+
+/// Dump every number up to `n`, with `flush` every line is written out on its own.
+fn print(n: u32, flush: bool) -> Vec<Inst> {
+    let mut instructions = vec![
+        Inst::StackOp(StackOp::Push(0)),
+
+        Inst::Label(0),
+        Inst::StackOp(StackOp::Push(1)),
+        Inst::BinaryExpr(ExprKind::Add),
+        Inst::StackOp(StackOp::Dup),
+        Inst::StackOp(StackOp::Dump),
+    ];
+
+    if flush {
+        instructions.push(Inst::Flush);
+    }
+
+    instructions.extend([
+        Inst::StackOp(StackOp::Dup),
+        Inst::StackOp(StackOp::Push(n)),
+        Inst::StackOp(StackOp::Cmp),
+        Inst::Jump(Jump::Lesser, 0),
+
+        Inst::StackOp(StackOp::Pop),
+        Inst::Halt,
+    ]);
+
+    instructions
+}
+
+fn compile(name: &str, instructions: Vec<Inst>) -> Result<Program, Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("stacked-bench-{name}.stck"));
+    let file = path.to_string_lossy();
+
+    let mut codegen = CodeGen::new(&file)?;
+
+    for inst in instructions {
+        codegen.append(inst);
+    }
+
+    codegen.output()?;
+
+    let program = Program::from_file(&file)?;
+
+    std::fs::remove_file(path)?;
+
+    Ok(program)
+}
+
+fn bench(name: &str, instructions: Vec<Inst>) -> Result<(), Box<dyn std::error::Error>> {
+    let program = compile(name, instructions)?;
+    let mut best = Duration::MAX;
+
+    for _ in 0..RUNS {
+        let mut vm = Machine::new(false).stdout(File::create("/dev/null")?);
+        let start = Instant::now();
+
+        vm.exec(&program).map_err(|err| err.to_string())?;
+
+        best = best.min(start.elapsed());
+    }
+
+    println!("{name:<24} {:>10.2?}", best);
+
+    Ok(())
+}
+
+/// Run one program parsed once on `THREADS` machines at the same time.
+fn bench_shared(name: &str, instructions: Vec<Inst>) -> Result<(), Box<dyn std::error::Error>> {
+    let program = Arc::new(compile(name, instructions)?);
+    let mut best = Duration::MAX;
+
+    for _ in 0..RUNS {
+        let start = Instant::now();
+
+        let workers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let program = Arc::clone(&program);
+
+                thread::spawn(move || {
+                    let mut vm = Machine::new(false);
+
+                    vm.load(program);
+                    vm.resume().map(|_| ()).map_err(|err| err.to_string())
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            worker.join().map_err(|_| "worker panicked")??;
+        }
+
+        best = best.min(start.elapsed());
+    }
+
+    println!("{:<24} {:>10.2?}", format!("{name} x{THREADS} threads"), best);
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    bench("fib(27)", fib(27))?;
+    bench("count(10M)", count(10_000_000))?;
+    bench("print(1M)", print(1_000_000, false))?;
+    bench("print+flush(1M)", print(1_000_000, true))?;
+    bench_shared("fib(27)", fib(27))?;
+
+    Ok(())
+}
+
@@ -1,39 +1,104 @@
 use lib_stacked::*;
 
-pub fn disassemble(instructions: Vec<Inst>) {
-    for inst in instructions {
-        match inst {
-            Inst::Label(_) => println!("0x4C {}", inst),
-
-            Inst::Call(_) => println!("0x2F {}", inst),
-
-            Inst::Jump(Jump::Unconditional, _) => println!("0x6A {}", inst),
-            Inst::Jump(Jump::Equal, _) => println!("0x6B {}", inst),
-            Inst::Jump(Jump::NotEqual, _) => println!("0x6E {}", inst),
-            Inst::Jump(Jump::Greater, _) => println!("0x6C {}", inst),
-            Inst::Jump(Jump::Lesser, _) => println!("0x6D {}", inst),
-
-            Inst::MemOp(MemOp::Load) => println!("0x8A {}", inst),
-            Inst::MemOp(MemOp::Store) => println!("0x8B {}", inst),
-            Inst::MemOp(MemOp::InsertStr(_)) => println!("0x8C {}", inst),
-
-            Inst::StackOp(StackOp::Push(_)) => println!("0x01 {}", inst),
-            Inst::StackOp(StackOp::Pop) => println!("0x02 {}", inst),
-            Inst::StackOp(StackOp::Dup) => println!("0x05 {}", inst),
-            Inst::StackOp(StackOp::Swap) => println!("0x06 {}", inst),
-            Inst::StackOp(StackOp::Rot) => println!("0x07 {}", inst),
-            Inst::StackOp(StackOp::Dump) => println!("0x03 {}", inst),
-            Inst::StackOp(StackOp::Cmp) => println!("0x43 {}", inst),
-
-            Inst::BinaryExpr(ExprKind::Add) => println!("0x28 {}", inst),
-            Inst::BinaryExpr(ExprKind::Sub) => println!("0x29 {}", inst),
-            Inst::BinaryExpr(ExprKind::Mul) => println!("0x2A {}", inst),
-            Inst::BinaryExpr(ExprKind::Div) => println!("0x2B {}", inst),
-
-            Inst::Syscall => println!("0x53 {}", inst),
-            Inst::Return => println!("0x0D {}", inst),
-            Inst::Halt => println!("0x04 {}", inst),
+use colored::Colorize;
+
+use std::collections::HashMap;
+
+pub fn disassemble(
+    instructions: Vec<Inst>,
+    data: Vec<Vec<u8>>,
+    functions: Vec<Function>,
+    symbols: HashMap<u32, String>,
+    lines: Vec<(usize, Span)>,
+) {
+    let mut lines = lines.into_iter().peekable();
+
+    for (index, inst) in instructions.into_iter().enumerate() {
+        while let Some((_, span)) = lines.next_if(|(start, _)| *start <= index) {
+            println!("{}", format!("-- {span}").dimmed());
+        }
+
+        if let Inst::Label(label) = inst {
+            if let Some(name) = symbols.get(&label) {
+                println!("{}:", name.blue());
+            }
+        }
+
+        let opcode: u8 = match inst {
+            Inst::Label(_) => 0x4C,
+
+            Inst::Call(_) => 0x2F,
+            Inst::CallIndirect => 0x2E,
+
+            Inst::Jump(Jump::Unconditional, _) => 0x6A,
+            Inst::Jump(Jump::Equal, _) => 0x6B,
+            Inst::Jump(Jump::NotEqual, _) => 0x6E,
+            Inst::Jump(Jump::Greater, _) => 0x6C,
+            Inst::Jump(Jump::Lesser, _) => 0x6D,
+            Inst::JumpTable(_) => 0x6F,
+
+            Inst::MemOp(MemOp::Load) => 0x8A,
+            Inst::MemOp(MemOp::Store) => 0x8B,
+            Inst::MemOp(MemOp::InsertStr(_)) => 0x8C,
+            Inst::MemOp(MemOp::InsertBytes(_)) => 0x8D,
+
+            Inst::LocalOp(LocalOp::Enter(_)) => 0x30,
+            Inst::LocalOp(LocalOp::Leave) => 0x31,
+            Inst::LocalOp(LocalOp::Load(_)) => 0x32,
+            Inst::LocalOp(LocalOp::Store(_)) => 0x33,
+
+            Inst::StackOp(StackOp::Push(_)) => 0x01,
+            Inst::StackOp(StackOp::PushData(_)) => 0x08,
+            Inst::StackOp(StackOp::PushFunc(_)) => 0x10,
+            Inst::StackOp(StackOp::Pop) => 0x02,
+            Inst::StackOp(StackOp::Dup) => 0x05,
+            Inst::StackOp(StackOp::Swap) => 0x06,
+            Inst::StackOp(StackOp::Rot) => 0x07,
+            Inst::StackOp(StackOp::Dump) => 0x03,
+            Inst::StackOp(StackOp::Cmp) => 0x43,
+            Inst::StackOp(StackOp::Over) => 0x09,
+            Inst::StackOp(StackOp::Nip) => 0x0A,
+            Inst::StackOp(StackOp::Tuck) => 0x0B,
+            Inst::StackOp(StackOp::Pick(_)) => 0x0C,
+            Inst::StackOp(StackOp::Roll(_)) => 0x0E,
+            Inst::StackOp(StackOp::Depth) => 0x0F,
+
+            Inst::BinaryExpr(ExprKind::Add) => 0x28,
+            Inst::BinaryExpr(ExprKind::Sub) => 0x29,
+            Inst::BinaryExpr(ExprKind::Mul) => 0x2A,
+            Inst::BinaryExpr(ExprKind::Div) => 0x2B,
+
+            Inst::Syscall => 0x53,
+            Inst::HostCall(_) => 0x54,
+            Inst::Flush => 0x55,
+            Inst::Return => 0x0D,
+            Inst::Halt => 0x04,
+        };
+
+        let names: Vec<&str> = inst.targets()
+            .iter()
+            .filter_map(|label| symbols.get(label).map(String::as_str))
+            .collect();
+
+        if names.is_empty() {
+            println!("0x{:02X} {}", opcode, inst);
+        } else {
+            println!("0x{:02X} {} {}", opcode, inst, format!("; {}", names.join(", ")).dimmed());
         }
     }
-}
 
+    for (id, item) in data.iter().enumerate() {
+        println!("#{} {}", id, format!("{:?}", String::from_utf8_lossy(item)).green());
+    }
+
+    for function in functions {
+        println!(
+            "{} <{}> ({} -> {}, {} locals)",
+            function.name.blue(),
+            function.label,
+            function.params,
+            function.results,
+            function.locals,
+        );
+    }
+}
@@ -1,8 +1,13 @@
-use crate::{Inst, ExprKind, Jump, StackOp, MemOp, log, syscall::{self, Syscall}};
+use crate::{Inst, Span, log, host::{HostContext, HostFunction}, loader::{OpCode, Program}, snapshot::{Snapshot, SnapshotError}, syscall::{self, Backend, Capture, FdTable, OsBackend, Stdio, Syscall, SyscallPolicy}};
 
 use std::collections::HashMap;
 use std::fmt;
-use std::io;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Arc;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 
 #[derive(Clone, Copy, Debug)]
 pub enum Value {
@@ -27,19 +32,196 @@ impl fmt::Display for Value {
     }
 }
 
+/// The amount of cells in the memory of a machine.
+pub const MEMORY_SIZE: usize = 10000;
+
+pub const DEFAULT_STACK_LIMIT: usize = 1 << 16;
+pub const DEFAULT_CALL_LIMIT: usize = 1 << 12;
+
+/// Amount of values from the top of the stack that are captured in an [`Error`].
+const STACK_SNAPSHOT: usize = 8;
+
+#[derive(Debug)]
 pub enum ErrorKind {
     UnknownLabel(u32),
-    Syscall(String),
+    /// A local slot that was not reserved by `Enter` in the current frame.
+    UnknownLocal(u32),
+    /// A function was called with fewer values on the stack than it declares parameters.
+    Arguments { function: Box<str>, params: u32, found: u32 },
+    /// A function returned with a different amount of values than it declares results.
+    Results { function: Box<str>, results: u32, found: i32 },
 
     UnknownSyscall,
+    /// A host call to an id no host function is registered with.
+    UnknownHost(u32),
+    /// A host function reported an error.
+    Host(String),
     StackUnderflow,
+    StackOverflow,
+    CallStackOverflow,
     OutOfBounds,
+    DivisionByZero,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnknownLabel(label) => write!(f, "unknown label `{label}`"),
+            ErrorKind::UnknownLocal(index) => write!(f, "unknown local `{index}`"),
+            ErrorKind::Arguments { function, params, found } => write!(f, "function `{function}` expects {params} arguments but the stack holds {found}"),
+            ErrorKind::Results { function, results, found } => write!(f, "function `{function}` declares {results} results but returns {found}"),
+            ErrorKind::UnknownSyscall => write!(f, "unknown syscall"),
+            ErrorKind::UnknownHost(id) => write!(f, "unknown host function `{id}`"),
+            ErrorKind::Host(err) => write!(f, "{err}"),
+            ErrorKind::StackUnderflow => write!(f, "stackunderflow"),
+            ErrorKind::StackOverflow => write!(f, "stackoverflow"),
+            ErrorKind::CallStackOverflow => write!(f, "call stackoverflow"),
+            ErrorKind::OutOfBounds => write!(f, "out of bounds"),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for ErrorKind {}
+
+/// An active call as reported in a backtrace.
+#[derive(Clone, Debug)]
+pub struct Trace {
+    /// The label that was called.
+    pub label: u32,
+    /// The name of the function or label that was called, if it has one.
+    pub name: Option<String>,
+    /// The instruction index execution continues at after returning.
+    pub ret: u32,
+    /// The source location of the call.
+    pub span: Option<Span>,
+}
+
+/// The result and the output of a run with captured streams.
+#[derive(Debug)]
+pub struct Output {
+    pub result: Result<(), Box<Error>>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Frame {
+    /// The instruction index that was called.
+    pub(crate) target: u32,
+    pub(crate) ret: u32,
+    /// Index of the first local slot of this frame.
+    pub(crate) base: usize,
+    /// Index of the called function in the function table, if it is declared.
+    pub(crate) function: Option<u32>,
+    /// The stack depth below the arguments of the innermost declared function, values below
+    /// it belong to its caller.
+    pub(crate) floor: usize,
+}
+
+/// A runtime error together with the state of the machine at the faulting instruction.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Index of the faulting instruction.
+    pub ip: u32,
+    /// Byte offset of the faulting instruction in the program.
+    pub offset: u32,
+    pub inst: Option<Inst>,
+    /// The source location of the faulting instruction.
+    pub span: Option<Span>,
+    /// The top of the stack, the last value is the top.
+    pub stack: Vec<Value>,
+    /// The active calls, the innermost call is first.
+    pub backtrace: Vec<Trace>,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        if let Some(span) = &self.span {
+            write!(f, "\n  at {span}")?;
+        }
+
+        match &self.inst {
+            Some(inst) => write!(f, "\n  at instruction {} (offset {:#x}): {:?}", self.ip, self.offset, inst)?,
+            None => write!(f, "\n  at instruction {} (offset {:#x})", self.ip, self.offset)?,
+        }
+
+        write!(f, "\n  stack: [{}]", self.stack.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(", "))?;
+
+        let mut frames: Vec<(&Trace, usize)> = Vec::new();
+
+        for frame in &self.backtrace {
+            match frames.last_mut() {
+                Some((last, count)) if last.label == frame.label && last.ret == frame.ret => *count += 1,
+                _ => frames.push((frame, 1)),
+            }
+        }
+
+        for (frame, count) in frames {
+            match &frame.name {
+                Some(name) => write!(f, "\n    in {name}")?,
+                None => write!(f, "\n    in <{}>", frame.label)?,
+            }
+
+            match &frame.span {
+                Some(span) => write!(f, " called from {span}")?,
+                None => write!(f, " called from instruction {}", frame.ret.saturating_sub(1))?,
+            }
+
+            if count > 1 {
+                write!(f, " (x{count})")?;
+            }
+        }
+
+        write!(f, "\n    in main")
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+
+// Machines are moved to other threads and share their programs with each other.
+const _: fn() = || {
+    fn send<T: Send>() {}
+    fn sync<T: Sync>() {}
+
+    send::<Machine>();
+    send::<Program>();
+    sync::<Program>();
+};
+
+/// Where a machine is in the execution of its program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Execution stopped before the next instruction and can be continued.
+    Paused,
+    /// The program halted or ran past its last instruction, or no program is loaded.
+    Halted,
+    /// An instruction failed, the error was returned by the call that executed it.
+    Failed,
 }
 
 pub struct Machine {
-    ret_stack: Vec<u32>,
+    ret_stack: Vec<Frame>,
+    /// The program loaded with [`Machine::load`].
+    program: Option<Arc<Program>>,
+    /// Index of the next instruction, kept between runs so execution can be resumed.
+    ip: u32,
+    status: Status,
+    locals: Vec<Value>,
     stack: Vec<Value>,
-    memory: [Value; 10000],
+    memory: [Value; MEMORY_SIZE],
+    stack_limit: usize,
+    call_limit: usize,
+    host: HashMap<u32, HostFunction>,
+    policy: SyscallPolicy,
+    fds: FdTable,
     debug: bool,
 }
 
@@ -47,42 +229,132 @@ impl Machine {
     pub fn new(debug: bool) -> Machine {
         Machine {
             ret_stack: Vec::new(),
+            program: None,
+            ip: 0,
+            status: Status::Halted,
+            locals: Vec::new(),
             stack: Vec::new(),
-            memory: [Value::Int(0); 10000],
+            memory: [Value::Int(0); MEMORY_SIZE],
+            stack_limit: DEFAULT_STACK_LIMIT,
+            call_limit: DEFAULT_CALL_LIMIT,
+            host: HashMap::new(),
+            policy: SyscallPolicy::allow_all(),
+            fds: FdTable::new(Box::new(OsBackend::new()), Stdio::default()),
             debug,
         }
     }
 
+    /// Set the maximum amount of values the operand stack can hold.
+    pub fn stack_limit(mut self, limit: usize) -> Machine {
+        self.stack_limit = limit;
+        self
+    }
+
+    /// Set the maximum depth of nested calls.
+    pub fn call_limit(mut self, limit: usize) -> Machine {
+        self.call_limit = limit;
+        self
+    }
+
+    /// Restrict the syscalls the machine makes, denied syscalls fail without reaching the host.
+    pub fn syscall_policy(mut self, policy: SyscallPolicy) -> Machine {
+        self.policy = policy;
+        self
+    }
+
+    /// Open the files of the guest through `backend` instead of the real filesystem, the
+    /// standard streams still belong to the host.
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Machine {
+        self.fds.set_backend(Box::new(backend));
+        self
+    }
+
+    /// Read descriptor 0 of the guest from `stdin` instead of the standard input of the host.
+    pub fn stdin(mut self, stdin: impl Read + Send + 'static) -> Machine {
+        self.fds.stdio.stdin = Box::new(stdin);
+        self
+    }
+
+    /// Write descriptor 1 of the guest and `Dump` to `stdout` instead of the standard output of
+    /// the host.
+    pub fn stdout(mut self, stdout: impl Write + Send + 'static) -> Machine {
+        self.fds.stdio.stdout = Box::new(stdout);
+        self
+    }
+
+    /// Write descriptor 2 of the guest to `stderr` instead of the standard error of the host.
+    pub fn stderr(mut self, stderr: impl Write + Send + 'static) -> Machine {
+        self.fds.stdio.stderr = Box::new(stderr);
+        self
+    }
+
+    /// Register a host function that `HostCall` invokes with `id`, replacing any previous one.
+    pub fn host_function(mut self, id: u32, function: HostFunction) -> Machine {
+        self.host.insert(id, function);
+        self
+    }
+
+    /// Pop the top of the stack, a declared function can not pop the values of its caller.
     fn pop(&mut self) -> Result<Value, ErrorKind> {
-        if self.stack.len() > 0 {
-            Ok(self.stack.pop().unwrap())
-        } else {
-            Err(ErrorKind::StackUnderflow)
+        if self.stack.len() <= self.floor() {
+            return Err(ErrorKind::StackUnderflow);
         }
+
+        self.stack.pop().ok_or(ErrorKind::StackUnderflow)
+    }
+
+    /// The value `depth` below the top of the stack.
+    fn peek(&self, depth: usize) -> Result<Value, ErrorKind> {
+        self.stack
+            .len()
+            .checked_sub(depth + 1)
+            .filter(|index| *index >= self.floor())
+            .map(|index| self.stack[index])
+            .ok_or(ErrorKind::StackUnderflow)
     }
 
-    fn jump(&mut self, labels: &HashMap<u32, u32>, ip: &mut u32, addr: u32) -> Result<(), ErrorKind> {
-        if let Some(addr) = labels.get(&addr) {
-            *ip = *addr;
+    /// The stack depth the current frame can not pop below.
+    fn floor(&self) -> usize {
+        self.ret_stack.last().map(|frame| frame.floor).unwrap_or(0)
+    }
+
+    /// Index of the first local slot of the current frame.
+    fn base(&self) -> usize {
+        self.ret_stack.last().map(|frame| frame.base).unwrap_or(0)
+    }
+
+    fn local(&self, index: u32) -> Result<usize, ErrorKind> {
+        let slot = self.base() + index as usize;
+
+        if slot < self.locals.len() {
+            Ok(slot)
         } else {
-            return Err(ErrorKind::UnknownLabel(addr));
+            Err(ErrorKind::UnknownLocal(index))
         }
+    }
 
-        Ok(())
+    fn push(&mut self, value: Value) -> Result<(), ErrorKind> {
+        if self.stack.len() < self.stack_limit {
+            self.stack.push(value);
+
+            Ok(())
+        } else {
+            Err(ErrorKind::StackOverflow)
+        }
     }
 
     fn strlen(&self, ptr: usize) -> usize {
         self.memory[ptr..]
             .iter()
-            .map(|value| if value.as_int() == 0 { return })
+            .take_while(|value| value.as_int() != 0)
             .count()
     }
 
-    fn to_string(&self, bytes: &[Value]) -> String {
-        bytes
+    fn to_bytes(&self, cells: &[Value]) -> Vec<u8> {
+        cells
             .iter()
-            .map(|byte| byte.as_int().clamp(0, 255) as u8 as char)
-            .collect::<String>()
+            .map(|cell| cell.as_int().clamp(0, 255) as u8)
+            .collect::<Vec<u8>>()
     }
 
     fn bound_check(&self, addr: u32) -> Result<(), ErrorKind> {
@@ -93,183 +365,615 @@ impl Machine {
         }
     }
 
-    pub fn exec(&mut self, instructions: Vec<Inst>, labels: HashMap<u32, u32>) -> Result<(), ErrorKind> {
-        let mut ip = 0;
+    /// The name and local slots of every frame, starting with main.
+    fn frames(&self, program: &Program) -> Vec<(String, &[Value])> {
+        let mut bases: Vec<(String, usize)> = vec![(String::from("main"), 0)];
+
+        for frame in &self.ret_stack {
+            let name = match program.name_at(frame.target) {
+                Some(name) => name.to_string(),
+                None => format!("<{}>", program.label_at(frame.target).unwrap_or(frame.target)),
+            };
+
+            bases.push((name, frame.base));
+        }
+
+        let ends: Vec<usize> = bases
+            .iter()
+            .skip(1)
+            .map(|(_, base)| *base)
+            .chain([self.locals.len()])
+            .collect();
+
+        bases
+            .into_iter()
+            .zip(ends)
+            .map(|((name, base), end)| (name, &self.locals[base..end]))
+            .collect()
+    }
+
+    fn error(&self, kind: ErrorKind, program: &Program, ip: u32) -> Box<Error> {
+        Box::new(Error {
+            kind,
+            ip,
+            offset: program.offset(ip) as u32,
+            inst: program.inst(ip),
+            span: program.span_at(ip).cloned(),
+            stack: self.stack[self.stack.len().saturating_sub(STACK_SNAPSHOT)..].to_vec(),
+            backtrace: self.ret_stack
+                .iter()
+                .rev()
+                .map(|frame| Trace {
+                    label: program.label_at(frame.target).unwrap_or(frame.target),
+                    name: program.name_at(frame.target).map(String::from),
+                    ret: frame.ret,
+                    span: program.span_at(frame.ret.saturating_sub(1)).cloned(),
+                })
+                .collect(),
+        })
+    }
+
+    /// Run `program` to completion without loading it, any loaded program is unloaded.
+    pub fn exec(&mut self, program: &Program) -> Result<(), Box<Error>> {
+        self.program = None;
+        self.start(program);
+        self.execute(program, None, None).map(|_| ())
+    }
+
+    /// Load a program to execute with [`Machine::step`], [`Machine::run`],
+    /// [`Machine::run_until`] and [`Machine::resume`], execution starts at its first instruction.
+    pub fn load(&mut self, program: Arc<Program>) {
+        self.start(&program);
+        self.program = Some(program);
+    }
+
+    /// Execute the next instruction.
+    pub fn step(&mut self) -> Result<Status, Box<Error>> {
+        self.run(1)
+    }
+
+    /// Execute at most `steps` instructions.
+    pub fn run(&mut self, steps: u64) -> Result<Status, Box<Error>> {
+        self.continue_with(Some(steps), None)
+    }
+
+    /// Execute until the instruction at the index `breakpoint` is next, it is not executed.
+    ///
+    /// A machine that is already stopped at the breakpoint executes it first, so calling this
+    /// repeatedly stops at the breakpoint every time it is reached.
+    pub fn run_until(&mut self, breakpoint: u32) -> Result<Status, Box<Error>> {
+        if self.ip == breakpoint && self.step()? != Status::Paused {
+            return Ok(self.status);
+        }
+
+        self.continue_with(None, Some(breakpoint))
+    }
+
+    /// Execute until the program halts or fails.
+    pub fn resume(&mut self) -> Result<Status, Box<Error>> {
+        self.continue_with(None, None)
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// Index of the next instruction.
+    pub fn ip(&self) -> u32 {
+        self.ip
+    }
+
+    /// The operand stack, the last value is the top.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn memory(&self) -> &[Value] {
+        &self.memory
+    }
+
+    /// Reset the state a previous run left behind and load the data of `program`.
+    fn start(&mut self, program: &Program) {
+        self.ip = 0;
+        self.status = Status::Paused;
+        self.stack.clear();
+        self.locals.clear();
+        self.ret_stack.clear();
+        self.memory.fill(Value::Int(0));
+        self.fds.reset();
+
+        for (offset, byte) in program.data.iter().enumerate() {
+            self.memory[program.data_base as usize + offset] = Value::Int(*byte as u32);
+        }
+    }
+
+    fn continue_with(&mut self, steps: Option<u64>, until: Option<u32>) -> Result<Status, Box<Error>> {
+        match self.program.clone() {
+            Some(program) => self.execute(&program, steps, until),
+            None => Ok(self.status),
+        }
+    }
+
+    fn execute(&mut self, program: &Program, steps: Option<u64>, until: Option<u32>) -> Result<Status, Box<Error>> {
+        if self.status != Status::Paused {
+            return Ok(self.status);
+        }
+
+        let mut ip = self.ip;
+        let result = self.dispatch(program, &mut ip, steps, until);
+
+        self.ip = ip;
+        self.status = match result {
+            Ok(true) => Status::Halted,
+            Ok(false) => Status::Paused,
+            Err(_) => Status::Failed,
+        };
+
+        // Output is written out whenever execution stops, whether it paused, halted or failed.
+        let _ = self.fds.flush();
+
+        result
+            .map(|_| self.status)
+            .map_err(|kind| self.error(kind, program, ip))
+    }
+
+    /// The state of the machine between two instructions.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            ip: self.ip,
+            stack: self.stack.iter().map(Value::as_int).collect(),
+            locals: self.locals.iter().map(Value::as_int).collect(),
+            frames: self.ret_stack.clone(),
+            memory: self.memory.iter().map(Value::as_int).collect(),
+            program: None,
+        }
+    }
+
+    /// Put back the state of a snapshot, the program it was taken of has to be loaded first.
+    /// Execution continues where the snapshot was taken.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let program = self.program.as_ref().ok_or(SnapshotError::NoProgram)?;
+        let len = program.code.len() as u32;
+
+        if snapshot.memory.len() != MEMORY_SIZE {
+            return Err(SnapshotError::MemorySize { expected: MEMORY_SIZE, found: snapshot.memory.len() });
+        }
+
+        if snapshot.ip >= len {
+            return Err(SnapshotError::InvalidIp { ip: snapshot.ip });
+        }
+
+        let mut base = 0;
+
+        for (index, frame) in snapshot.frames.iter().enumerate() {
+            // A call as the last instruction returns to the end of the program, which `Return`
+            // reports as out of bounds.
+            let valid = frame.target < len
+                && frame.ret <= len
+                && frame.function.is_none_or(|function| (function as usize) < program.functions.len())
+                && frame.base >= base
+                && frame.base <= snapshot.locals.len()
+                && frame.floor <= snapshot.stack.len();
+
+            if !valid {
+                return Err(SnapshotError::InvalidFrame { index });
+            }
+
+            base = frame.base;
+        }
+
+        self.ip = snapshot.ip;
+        self.status = Status::Paused;
+        self.stack = snapshot.stack.iter().copied().map(Value::Int).collect();
+        self.locals = snapshot.locals.iter().copied().map(Value::Int).collect();
+        self.ret_stack = snapshot.frames.clone();
+
+        for (cell, value) in self.memory.iter_mut().zip(&snapshot.memory) {
+            *cell = Value::Int(*value);
+        }
+
+        Ok(())
+    }
+
+    /// Execute with `stdin` as standard input and capture standard output and standard error,
+    /// the previous streams are restored afterwards.
+    pub fn run_capture(&mut self, program: &Program, stdin: &[u8]) -> Output {
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+
+        let previous = mem::replace(&mut self.fds.stdio, Stdio {
+            stdin: Box::new(io::Cursor::new(stdin.to_vec())),
+            stdout: Box::new(stdout.clone()),
+            stderr: Box::new(stderr.clone()),
+        });
+
+        let result = self.exec(program);
+
+        self.fds.stdio = previous;
+
+        Output { result, stdout: stdout.take(), stderr: stderr.take() }
+    }
+
+    /// Push the frame of a call to the instruction at `target`.
+    fn call(&mut self, target: u32, ret: u32) -> Result<(), ErrorKind> {
+        if self.ret_stack.len() >= self.call_limit {
+            return Err(ErrorKind::CallStackOverflow);
+        }
+
+        self.ret_stack.push(Frame { target, ret, base: self.locals.len(), function: None, floor: self.floor() });
+
+        Ok(())
+    }
+
+    /// Push the frame of a call to the function at `index` of the function table after checking
+    /// its arguments, returns the instruction index the function starts at.
+    fn call_function(&mut self, program: &Program, index: u32, ret: u32) -> Result<u32, ErrorKind> {
+        let callable = &program.functions[index as usize];
+        let function = &callable.function;
+
+        let available = self.stack.len() - self.floor();
+
+        if available < function.params as usize {
+            return Err(ErrorKind::Arguments { function: function.name.as_str().into(), params: function.params, found: available as u32 });
+        }
+
+        if self.ret_stack.len() >= self.call_limit {
+            return Err(ErrorKind::CallStackOverflow);
+        }
+
+        if self.locals.len() + function.locals as usize > self.stack_limit {
+            return Err(ErrorKind::StackOverflow);
+        }
+
+        self.ret_stack.push(Frame {
+            target: callable.target,
+            ret,
+            base: self.locals.len(),
+            function: Some(index),
+            floor: self.stack.len() - function.params as usize,
+        });
+        self.locals.resize(self.locals.len() + function.locals as usize, Value::Int(0));
+
+        Ok(callable.target)
+    }
+
+    fn binary(&mut self, op: fn(u32, u32) -> Result<u32, ErrorKind>) -> Result<(), ErrorKind> {
+        let rhs = self.pop()?.as_int();
+        let lhs = self.pop()?.as_int();
+
+        self.push(Value::Int(op(lhs, rhs)?))
+    }
+
+    /// Returns whether the program stopped, `false` means the steps ran out or the instruction
+    /// at `until` is next.
+    fn dispatch(&mut self, program: &Program, ip: &mut u32, steps: Option<u64>, until: Option<u32>) -> Result<bool, ErrorKind> {
+        let code = &program.code;
+        // Plain values keep the checks cheap, no program runs `u64::MAX` steps or has an
+        // instruction at `u32::MAX`.
+        let mut remaining = steps.unwrap_or(u64::MAX);
+        let until = until.unwrap_or(u32::MAX);
+
+        while (*ip as usize) < code.len() {
+            if remaining == 0 || *ip == until {
+                return Ok(false);
+            }
+
+            remaining -= 1;
+
+            let op = code[*ip as usize];
 
-        while ip < instructions.len() as u32 {
             if self.debug {
-                log::info(&format!("Inst: {:?}", instructions[ip as usize]));
+                if let Some(label) = program.label_at(*ip) {
+                    match program.name_at(*ip) {
+                        Some(name) => log::info(&format!("Label: <{label}> {name}")),
+                        None => log::info(&format!("Label: <{label}>")),
+                    }
+                }
+
+                match program.span_at(*ip) {
+                    Some(span) => log::info(&format!("Inst: {:?} at {}", program.inst(*ip), span)),
+                    None => log::info(&format!("Inst: {:?}", program.inst(*ip))),
+                }
             }
 
-            match &instructions[ip as usize] {
-                Inst::BinaryExpr(expr) => {
-                    let rhs = self.pop()?;
-                    let lhs = self.pop()?;
-
-                    self.stack.push(
-                        Value::Int(
-                            match expr {
-                                ExprKind::Add => {
-                                    lhs.as_int() + rhs.as_int()
-                                },
-                                ExprKind::Sub => {
-                                    lhs.as_int() - rhs.as_int()
-                                },
-                                ExprKind::Mul => {
-                                    lhs.as_int() * rhs.as_int()
-                                },
-                                ExprKind::Div => {
-                                    lhs.as_int() / rhs.as_int()
-                                },
-                            }
-                        )
-                    );
+            match op.code {
+                OpCode::Push | OpCode::PushFunc => {
+                    self.push(Value::Int(op.arg))?;
+                },
+                OpCode::Pop => {
+                    self.pop()?;
+                },
+                OpCode::Dup => {
+                    self.push(self.peek(0)?)?;
+                },
+                OpCode::Swap => {
+                    let a = self.pop()?;
+                    let b = self.pop()?;
+
+                    self.push(a)?;
+                    self.push(b)?;
+                },
+                OpCode::Rot => {
+                    let a = self.pop()?;
+                    let b = self.pop()?;
+                    let c = self.pop()?;
+
+                    self.push(a)?;
+                    self.push(b)?;
+                    self.push(c)?;
+                },
+                OpCode::Over => {
+                    self.push(self.peek(1)?)?;
+                },
+                OpCode::Nip => {
+                    let top = self.pop()?;
+
+                    self.pop()?;
+                    self.push(top)?;
+                },
+                OpCode::Tuck => {
+                    let a = self.pop()?;
+                    let b = self.pop()?;
+
+                    self.push(a)?;
+                    self.push(b)?;
+                    self.push(a)?;
                 },
-                Inst::Call(addr) => {
-                    self.ret_stack.push(ip + 1);
-                    self.jump(&labels, &mut ip, *addr)?;
+                OpCode::Pick => {
+                    self.push(self.peek(op.arg as usize)?)?;
+                },
+                OpCode::Roll => {
+                    self.peek(op.arg as usize)?;
+
+                    let value = self.stack.remove(self.stack.len() - 1 - op.arg as usize);
+
+                    self.stack.push(value);
+                },
+                OpCode::Depth => {
+                    self.push(Value::Int(self.stack.len() as u32))?;
+                },
+                OpCode::Dump => {
+                    let value = self.pop()?;
+
+                    // A guest can not observe a failed `Dump`.
+                    let _ = self.fds.dump(value);
+                },
+                OpCode::Cmp => {
+                    let rhs = self.pop()?.as_int();
+                    let lhs = self.pop()?.as_int();
+
+                    if lhs == rhs {
+                        self.push(Value::Int(0))?;
+                    } else if lhs > rhs {
+                        self.push(Value::Int(1))?;
+                    } else {
+                        self.push(Value::Int(2))?;
+                    }
+                },
+                OpCode::Add => self.binary(|lhs, rhs| Ok(lhs.wrapping_add(rhs)))?,
+                OpCode::Sub => self.binary(|lhs, rhs| Ok(lhs.wrapping_sub(rhs)))?,
+                OpCode::Mul => self.binary(|lhs, rhs| Ok(lhs.wrapping_mul(rhs)))?,
+                OpCode::Div => self.binary(|lhs, rhs| lhs.checked_div(rhs).ok_or(ErrorKind::DivisionByZero))?,
+                OpCode::Call => {
+                    self.call(op.arg, *ip + 1)?;
+                    *ip = op.arg;
                     continue;
                 },
-                Inst::Jump(jump, addr) => {
-                    let result = if *jump != Jump::Unconditional {
-                        self.pop()?.as_int()
+                OpCode::CallFunction => {
+                    *ip = self.call_function(program, op.arg, *ip + 1)?;
+                    continue;
+                },
+                OpCode::CallIndirect => {
+                    let label = self.pop()?.as_int();
+
+                    if let Some(index) = program.declared.get(&label) {
+                        *ip = self.call_function(program, *index, *ip + 1)?;
                     } else {
-                        0
-                    };
+                        let target = program.symbols.get(&label).copied().ok_or(ErrorKind::UnknownLabel(label))?;
 
-                    if match jump {
-                        Jump::Unconditional => true,
-                        Jump::Equal => result == 0,
-                        Jump::NotEqual => result != 0,
-                        Jump::Greater => result == 1,
-                        Jump::Lesser => result == 2,
-                    } {
-                        self.jump(&labels, &mut ip, *addr)?;
+                        self.call(target, *ip + 1)?;
+                        *ip = target;
+                    }
+
+                    continue;
+                },
+                OpCode::JumpTable => {
+                    let index = self.pop()?.as_int();
+
+                    if let Some(target) = program.tables[op.arg as usize].get(index as usize) {
+                        *ip = *target;
                         continue;
                     }
                 },
-                Inst::StackOp(stackop) => {
-                    match stackop {
-                        StackOp::Push(integer) => {
-                            self.stack.push(Value::Int(*integer));
-                        },
-                        StackOp::Pop => {
-                            self.pop()?;
-                        },
-                        StackOp::Dup => {
-                            if !self.stack.is_empty() {
-                                let value = self.pop()?;
-                                for _ in 0..2 {
-                                    self.stack.push(value);
-                                }
-                            }
-                        },
-                        StackOp::Rot | StackOp::Swap => {
-                            let values = if *stackop == StackOp::Rot {
-                                [self.pop()?, self.pop()?, self.pop()?].to_vec()
-                            } else {
-                                [self.pop()?, self.pop()?].to_vec()
-                            };
+                OpCode::Jump => {
+                    *ip = op.arg;
+                    continue;
+                },
+                OpCode::JumpEqual | OpCode::JumpNotEqual | OpCode::JumpGreater | OpCode::JumpLesser => {
+                    let result = self.pop()?.as_int();
 
-                            for value in values {
-                                self.stack.push(value);
-                            }
-                        },
-                        StackOp::Dump => {
-                            println!("{}", self.pop()?);
-                        },
-                        StackOp::Cmp => {
-                            let rhs = self.pop()?.as_int();
-                            let lhs = self.pop()?.as_int();
-
-                            if lhs == rhs {
-                                self.stack.push(Value::Int(0));
-                            } else if lhs > rhs {
-                                self.stack.push(Value::Int(1));
-                            } else {
-                                self.stack.push(Value::Int(2));
-                            }
-                        },
+                    if match op.code {
+                        OpCode::JumpEqual => result == 0,
+                        OpCode::JumpNotEqual => result != 0,
+                        OpCode::JumpGreater => result == 1,
+                        _ => result == 2,
+                    } {
+                        *ip = op.arg;
+                        continue;
                     }
                 },
-                Inst::MemOp(op) => {
+                OpCode::InsertStr | OpCode::InsertBytes | OpCode::Load | OpCode::Store => {
                     let addr = self.pop()?.as_int();
 
                     self.bound_check(addr)?;
 
-                    match op {
-                        MemOp::InsertStr(string) => {
-                            for (offset, character) in string.chars().enumerate() {
-                                self.memory[addr as usize + offset] = Value::Int(character as u8 as u32);
+                    match op.code {
+                        OpCode::InsertStr | OpCode::InsertBytes => {
+                            let bytes = &program.strings[op.arg as usize];
+
+                            self.bound_check(addr + bytes.len().saturating_sub(1) as u32)?;
+
+                            for (offset, byte) in bytes.iter().enumerate() {
+                                self.memory[addr as usize + offset] = Value::Int(*byte as u32);
+                            }
+
+                            if op.code == OpCode::InsertBytes {
+                                self.push(Value::Int(bytes.len() as u32))?;
                             }
                         },
-                        MemOp::Load => {
-                            self.stack.push(self.memory[addr as usize]);
+                        OpCode::Load => {
+                            self.push(self.memory[addr as usize])?;
                         },
-                        MemOp::Store => {
+                        _ => {
                             self.memory[addr as usize] = self.pop()?;
                         },
                     }
                 },
-                Inst::Syscall => {
+                OpCode::Enter => {
+                    if self.locals.len() + op.arg as usize > self.stack_limit {
+                        return Err(ErrorKind::StackOverflow);
+                    }
+
+                    self.locals.resize(self.locals.len() + op.arg as usize, Value::Int(0));
+                },
+                OpCode::Leave => {
+                    self.locals.truncate(self.base());
+                },
+                OpCode::LoadLocal => {
+                    self.push(self.locals[self.local(op.arg)?])?;
+                },
+                OpCode::StoreLocal => {
+                    let slot = self.local(op.arg)?;
+
+                    self.locals[slot] = self.pop()?;
+                },
+                OpCode::Syscall => {
                     let syscall = Syscall::from(self.pop()?.as_int());
 
-                    match syscall {
+                    let result = match syscall {
                         Syscall::Read | Syscall::Write => {
                             let fd = self.pop()?.as_int();
                             let buf = self.pop()?.as_int();
                             let count = self.pop()?.as_int();
 
-                            if let Err(err) = match syscall {
-                                Syscall::Read => syscall::read(fd as usize, &mut self.memory[buf as usize..buf as usize + count as usize]),
-                                Syscall::Write => syscall::write(fd as usize, &mut self.memory[buf as usize..buf as usize + count as usize]),
-                                _ => unreachable!(),
-                            } {
-                                return Err(ErrorKind::Syscall(err.to_string()));
+                            self.bound_check(buf)?;
+                            self.bound_check(buf.saturating_add(count.saturating_sub(1)))?;
+
+                            let buf = &mut self.memory[buf as usize..buf as usize + count as usize];
+
+                            if !self.policy.allows(syscall) {
+                                None
+                            } else if syscall == Syscall::Read {
+                                self.fds.read(fd, buf).ok().map(|count| count as u32)
+                            } else {
+                                self.fds.write(fd, buf).ok().map(|count| count as u32)
                             }
                         },
                         Syscall::Open => {
-                            let ptr = self.pop()?.as_int() as usize;
-                            let flags = self.pop()?.as_int() as usize;
-                            let filename = self.to_string(&self.memory[ptr..ptr + self.strlen(ptr)]);
+                            let ptr = self.pop()?.as_int();
+                            let flags = self.pop()?.as_int() as i32;
+
+                            self.bound_check(ptr)?;
+
+                            let ptr = ptr as usize;
+                            let filename = self.to_bytes(&self.memory[ptr..ptr + self.strlen(ptr)]);
+
+                            let path = Path::new(OsStr::from_bytes(&filename));
 
-                            if let Err(status) = syscall::open(&filename, flags) {
-                                return Err(ErrorKind::Syscall(format!("open failed with status {}", status)));
+                            if self.policy.allows_open(self.fds.backend(), path, flags) {
+                                self.fds.open(path, flags).ok()
+                            } else {
+                                None
                             }
                         },
                         Syscall::Close => {
                             let fd = self.pop()?.as_int();
 
-                            if let Err(status) = syscall::close(fd as usize) {
-                                return Err(ErrorKind::Syscall(format!("close failed with status {}", status)));
+                            if self.policy.allows(syscall) {
+                                self.fds.close(fd).ok().map(|_| 0)
+                            } else {
+                                None
                             }
                         },
                         Syscall::Unknown => {
                             return Err(ErrorKind::UnknownSyscall);
                         },
+                    };
+
+                    self.push(Value::Int(result.unwrap_or(syscall::FAILED)))?;
+                },
+                OpCode::HostCall => {
+                    let function = self.host.get_mut(&op.arg).ok_or(ErrorKind::UnknownHost(op.arg))?;
+                    let params = function.params as usize;
+                    let available = self.stack.len() - self.ret_stack.last().map(|frame| frame.floor).unwrap_or(0);
+
+                    if available < params {
+                        return Err(ErrorKind::Arguments { function: function.name.as_str().into(), params: function.params, found: available as u32 });
+                    }
+
+                    let args: Vec<u32> = self.stack
+                        .drain(self.stack.len() - params..)
+                        .map(|value| value.as_int())
+                        .collect();
+
+                    let mut context = HostContext::new(args, function.results, &mut self.memory);
+
+                    (function.func)(&mut context)
+                        .map_err(|err| ErrorKind::Host(format!("host function `{}`: {}", function.name, err)))?;
+
+                    let results = context.into_results();
+
+                    if results.len() != function.results as usize {
+                        return Err(ErrorKind::Results { function: function.name.as_str().into(), results: function.results, found: results.len() as i32 });
+                    }
+
+                    for value in results {
+                        self.push(Value::Int(value))?;
                     }
                 },
-                Inst::Halt => {
-                    return Ok(());
+                OpCode::Flush => {
+                    let _ = self.fds.flush();
+                },
+                OpCode::Halt => {
+                    return Ok(true);
                 },
-                Inst::Return => {
-                    let addr = self.ret_stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                OpCode::Return => {
+                    if let Some(frame) = self.ret_stack.last() {
+                        if let Some(index) = frame.function {
+                            let function = &program.functions[index as usize].function;
+                            let found = self.stack.len() as i32 - frame.floor as i32;
 
-                    if addr >= instructions.len() as u32 {
+                            if found != function.results as i32 {
+                                return Err(ErrorKind::Results { function: function.name.as_str().into(), results: function.results, found });
+                            }
+                        }
+                    }
+
+                    let frame = self.ret_stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                    let addr = frame.ret;
+
+                    self.locals.truncate(frame.base);
+
+                    if addr >= code.len() as u32 {
                         return Err(ErrorKind::OutOfBounds);
                     } else {
-                        ip = addr;
+                        *ip = addr;
                         continue;
                     }
                 },
-                Inst::Label(_) => (),
             }
 
             if self.debug {
-                log::info(&format!("======"));
+                let _ = self.fds.flush();
+
+                log::info("======");
                 log::info(&format!("Stack: {:?}", self.stack));
                 log::info(&format!("Return: {:?}", self.ret_stack));
-                log::info(&format!("======"));
+
+                for (name, locals) in self.frames(program) {
+                    log::info(&format!("Locals {}: {:?}", name, locals));
+                }
+                log::info("======");
 
                 loop {
                     let mut buf = String::new();
@@ -284,11 +988,293 @@ impl Machine {
                 }
             }
 
-            ip += 1;
+            *ip += 1;
         }
 
-        Ok(())
+        Ok(true)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Inst, StackOp, LocalOp, MemOp, ExprKind, Function, loader};
+
+    fn load(instructions: Vec<Inst>) -> Program {
+        loader::load(instructions, Vec::new(), Vec::new(), HashMap::new(), Vec::new()).unwrap()
+    }
+
+    fn push(value: u32) -> Inst {
+        Inst::StackOp(StackOp::Push(value))
+    }
+
+    fn dump() -> Inst {
+        Inst::StackOp(StackOp::Dump)
+    }
+
+    #[test]
+    fn reused_machine_gets_fresh_descriptors() {
+        let open = [
+            push(50), Inst::MemOp(MemOp::InsertStr(String::from("/file"))),
+            push(0), push(50), push(2), Inst::Syscall, dump(),
+        ];
+        let close = load([open.as_slice(), &[push(1), push(3), Inst::Syscall, Inst::Halt]].concat());
+        let write = load([open.as_slice(), &[
+            push(120), push(0), Inst::MemOp(MemOp::Store),
+            push(1), push(0), push(1), push(1), Inst::Syscall, dump(),
+        ]].concat());
+
+        let fs = crate::syscall::MemoryFs::new();
+
+        fs.insert_file("/file", "contents");
+
+        let mut vm = Machine::new(false).backend(fs);
+
+        assert_eq!(vm.run_capture(&close, b"").stdout, b"3\n");
+        assert_eq!(vm.run_capture(&write, b"").stdout, b"3\nx1\n");
+    }
+
+    #[test]
+    fn declared_functions_can_not_pop_their_callers_values() {
+        let program = |body: Vec<Inst>| {
+            let instructions = [
+                vec![push(5), push(1), Inst::Call(0), dump(), dump(), Inst::Halt, Inst::Label(0)],
+                body,
+                vec![Inst::Return, Inst::Label(1), Inst::StackOp(StackOp::Pop), Inst::Return],
+            ].concat();
+            let functions = vec![Function { label: 0, name: String::from("f"), params: 1, results: 1, locals: 0 }];
+
+            loader::load(instructions, Vec::new(), functions, HashMap::new(), Vec::new()).unwrap()
+        };
+        let run = |body: Vec<Inst>| Machine::new(false).run_capture(&program(body), b"");
+
+        let output = run(vec![push(1), Inst::BinaryExpr(ExprKind::Add)]);
+
+        assert!(output.result.is_ok());
+        assert_eq!(output.stdout, b"2\n5\n");
+
+        let underflows = [
+            vec![Inst::StackOp(StackOp::Pop), Inst::StackOp(StackOp::Pop), push(1), push(2)],
+            vec![Inst::StackOp(StackOp::Over), Inst::StackOp(StackOp::Pop)],
+            vec![Inst::StackOp(StackOp::Roll(1))],
+            // An undeclared helper can not pop them either.
+            vec![Inst::StackOp(StackOp::Pop), Inst::Call(1), push(1)],
+        ];
+
+        for body in underflows {
+            assert!(matches!(run(body).result.unwrap_err().kind, ErrorKind::StackUnderflow));
+        }
+    }
+
+    /// The stack a program halts with, or the kind of error it fails with.
+    fn stack_after(instructions: Vec<Inst>) -> Result<Vec<u32>, ErrorKind> {
+        let mut vm = Machine::new(false);
+
+        vm.exec(&load(instructions)).map_err(|err| err.kind)?;
+
+        Ok(vm.stack().iter().map(|value| value.as_int()).collect())
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        let values = || vec![push(1), push(2), push(3)];
+        let with = |op: StackOp| [values(), vec![Inst::StackOp(op)]].concat();
+
+        assert_eq!(stack_after(with(StackOp::Dup)).ok(), Some(vec![1, 2, 3, 3]));
+        assert_eq!(stack_after(with(StackOp::Over)).ok(), Some(vec![1, 2, 3, 2]));
+        assert_eq!(stack_after(with(StackOp::Nip)).ok(), Some(vec![1, 3]));
+        assert_eq!(stack_after(with(StackOp::Tuck)).ok(), Some(vec![1, 3, 2, 3]));
+        assert_eq!(stack_after(with(StackOp::Pick(0))).ok(), Some(vec![1, 2, 3, 3]));
+        assert_eq!(stack_after(with(StackOp::Pick(2))).ok(), Some(vec![1, 2, 3, 1]));
+        assert_eq!(stack_after(with(StackOp::Roll(1))).ok(), Some(vec![1, 3, 2]));
+        assert_eq!(stack_after(with(StackOp::Roll(2))).ok(), Some(vec![2, 3, 1]));
+        assert_eq!(stack_after(with(StackOp::Depth)).ok(), Some(vec![1, 2, 3, 3]));
+        assert_eq!(stack_after(vec![Inst::StackOp(StackOp::Depth)]).ok(), Some(vec![0]));
+
+        let underflows = [
+            vec![Inst::StackOp(StackOp::Dup)],
+            vec![push(1), Inst::StackOp(StackOp::Over)],
+            vec![push(1), Inst::StackOp(StackOp::Nip)],
+            vec![push(1), Inst::StackOp(StackOp::Tuck)],
+            with(StackOp::Pick(3)),
+            with(StackOp::Roll(3)),
+        ];
+
+        for instructions in underflows {
+            assert!(matches!(stack_after(instructions), Err(ErrorKind::StackUnderflow)));
+        }
+    }
+
+    #[test]
+    fn jump_tables() {
+        let table = |index: u32| load(vec![
+            push(index), Inst::JumpTable(vec![0, 1]),
+            push(9), dump(), Inst::Halt,
+            Inst::Label(0), push(10), dump(), Inst::Halt,
+            Inst::Label(1), push(11), dump(), Inst::Halt,
+        ]);
+        let run = |index: u32| Machine::new(false).run_capture(&table(index), b"").stdout;
+
+        assert_eq!(run(0), b"10\n");
+        assert_eq!(run(1), b"11\n");
+        // An index out of range continues with the next instruction.
+        assert_eq!(run(2), b"9\n");
+    }
+
+    #[test]
+    fn indirect_calls() {
+        let program = |reference: Inst, functions: Vec<Function>| {
+            let instructions = vec![
+                push(4), reference, Inst::CallIndirect, dump(), Inst::Halt,
+                Inst::Label(0), push(1), Inst::BinaryExpr(ExprKind::Add), Inst::Return,
+            ];
+
+            loader::load(instructions, Vec::new(), functions, HashMap::new(), Vec::new()).unwrap()
+        };
+        let function = |params: u32| Function { label: 0, name: String::from("inc"), params, results: 1, locals: 0 };
+        let run = |program: Program| Machine::new(false).run_capture(&program, b"");
+
+        let undeclared = run(program(Inst::StackOp(StackOp::PushFunc(0)), Vec::new()));
+
+        assert!(undeclared.result.is_ok());
+        assert_eq!(undeclared.stdout, b"5\n");
+
+        let declared = run(program(Inst::StackOp(StackOp::PushFunc(0)), vec![function(1)]));
+
+        assert!(declared.result.is_ok());
+        assert_eq!(declared.stdout, b"5\n");
+
+        // The signature of a declared function is checked on indirect calls as well.
+        let arguments = run(program(Inst::StackOp(StackOp::PushFunc(0)), vec![function(2)])).result.unwrap_err();
+
+        assert!(matches!(arguments.kind, ErrorKind::Arguments { params: 2, found: 1, .. }));
+
+        let unknown = run(program(push(7), Vec::new())).result.unwrap_err();
+
+        assert!(matches!(unknown.kind, ErrorKind::UnknownLabel(7)));
+        assert_eq!(unknown.ip, 2);
+    }
+
+    #[test]
+    fn host_calls() {
+        let machine = || Machine::new(false)
+            .host_function(0, HostFunction::new("sub", 2, 1, |ctx| {
+                let (lhs, rhs) = (ctx.args()[0], ctx.args()[1]);
+
+                ctx.push(lhs - rhs)?;
+                Ok(())
+            }))
+            .host_function(1, HostFunction::new("double", 1, 1, |ctx| {
+                let addr = ctx.args()[0];
+                let value = ctx.load(addr)?;
+
+                ctx.store(addr + 1, value * 2)?;
+                ctx.push(value)?;
+                Ok(())
+            }))
+            .host_function(2, HostFunction::new("greedy", 0, 1, |ctx| {
+                ctx.push(1)?;
+                ctx.push(2)?;
+                Ok(())
+            }))
+            .host_function(3, HostFunction::new("lazy", 0, 1, |_| Ok(())))
+            .host_function(4, HostFunction::new("oob", 0, 0, |ctx| {
+                ctx.load(MEMORY_SIZE as u32)?;
+                Ok(())
+            }));
+        let run = |instructions: Vec<Inst>| machine().run_capture(&load(instructions), b"");
+
+        // The first argument was the deepest on the stack.
+        assert_eq!(run(vec![push(10), push(3), Inst::HostCall(0), dump()]).stdout, b"7\n");
+
+        let memory = run(vec![
+            push(21), push(100), Inst::MemOp(MemOp::Store),
+            push(100), Inst::HostCall(1), dump(),
+            push(101), Inst::MemOp(MemOp::Load), dump(),
+        ]);
 
+        assert_eq!(memory.stdout, b"21\n42\n");
+
+        let error = |instructions: Vec<Inst>| run(instructions).result.unwrap_err().kind;
+
+        assert!(matches!(error(vec![push(1), Inst::HostCall(0)]), ErrorKind::Arguments { params: 2, found: 1, .. }));
+        assert!(matches!(error(vec![Inst::HostCall(2)]), ErrorKind::Host(err) if err.contains("pushed more than the 1 declared results")));
+        assert!(matches!(error(vec![Inst::HostCall(3)]), ErrorKind::Results { results: 1, found: 0, .. }));
+        assert!(matches!(error(vec![Inst::HostCall(4)]), ErrorKind::Host(err) if err.contains("out of bounds")));
+        assert!(matches!(error(vec![Inst::HostCall(9)]), ErrorKind::UnknownHost(9)));
+    }
+
+    #[test]
+    fn arithmetic_wraps_and_division_by_zero_fails() {
+        let program = load(vec![
+            push(0), push(1), Inst::BinaryExpr(ExprKind::Sub), dump(),
+            push(u32::MAX), push(2), Inst::BinaryExpr(ExprKind::Add), dump(),
+            push(1 << 31), push(4), Inst::BinaryExpr(ExprKind::Mul), dump(),
+            push(7), push(0), Inst::BinaryExpr(ExprKind::Div),
+        ]);
+        let output = Machine::new(false).run_capture(&program, b"");
+        let err = output.result.unwrap_err();
+
+        assert_eq!(output.stdout, b"4294967295\n1\n0\n");
+        assert!(matches!(err.kind, ErrorKind::DivisionByZero));
+        assert_eq!(err.ip, 14);
+    }
+
+    #[test]
+    fn reused_machine_starts_clean() {
+        let dirty = load(vec![
+            Inst::StackOp(StackOp::Push(7)),
+            Inst::StackOp(StackOp::Push(100)),
+            Inst::MemOp(MemOp::Store),
+            Inst::StackOp(StackOp::Push(1)),
+            Inst::LocalOp(LocalOp::Enter(1)),
+            Inst::Call(0),
+            Inst::Halt,
+            Inst::Label(0),
+            Inst::Halt,
+        ]);
+        let clean = load(vec![
+            Inst::StackOp(StackOp::Depth),
+            Inst::StackOp(StackOp::Dump),
+            Inst::StackOp(StackOp::Push(100)),
+            Inst::MemOp(MemOp::Load),
+            Inst::StackOp(StackOp::Dump),
+            Inst::LocalOp(LocalOp::Load(0)),
+        ]);
+        let ret = load(vec![Inst::Return]);
+
+        let mut vm = Machine::new(false);
+
+        assert!(vm.run_capture(&dirty, b"").result.is_ok());
+
+        let output = vm.run_capture(&clean, b"");
+
+        assert_eq!(output.stdout, b"0\n0\n");
+        assert!(matches!(output.result.unwrap_err().kind, ErrorKind::UnknownLocal(0)));
+
+        assert!(vm.run_capture(&dirty, b"").result.is_ok());
+        assert!(matches!(vm.run_capture(&ret, b"").result.unwrap_err().kind, ErrorKind::StackUnderflow));
+    }
+
+    #[test]
+    fn stack_limits() {
+        let pushes = load(vec![push(1), push(2), push(3), Inst::Halt]);
+        let err = Machine::new(false).stack_limit(2).run_capture(&pushes, b"").result.unwrap_err();
+
+        assert!(matches!(err.kind, ErrorKind::StackOverflow));
+        assert_eq!(err.ip, 2);
+        assert_eq!(err.stack.iter().map(Value::as_int).collect::<Vec<u32>>(), vec![1, 2]);
+
+        let recursion = load(vec![Inst::Call(0), Inst::Halt, Inst::Label(0), Inst::Call(0), Inst::Return]);
+        let err = Machine::new(false).call_limit(4).run_capture(&recursion, b"").result.unwrap_err();
+
+        assert!(matches!(err.kind, ErrorKind::CallStackOverflow));
+        assert_eq!(err.backtrace.len(), 4);
+        assert!(err.backtrace.iter().all(|frame| frame.label == 0));
+
+        let message = err.to_string();
+
+        assert_eq!(message.matches("\n    in <0>").count(), 2);
+        assert!(message.contains(" (x3)"));
+    }
+}
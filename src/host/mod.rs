@@ -0,0 +1,131 @@
+use crate::exec::Value;
+
+use std::fmt;
+
+/// The error a host function reports, it aborts execution.
+pub type HostResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostError {
+    /// An access past the end of guest memory.
+    OutOfBounds { addr: u32, len: u32 },
+    /// More results were pushed than the function declares.
+    TooManyResults { results: u32 },
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::OutOfBounds { addr, len } => write!(f, "access of {len} cells at {addr} is out of bounds"),
+            HostError::TooManyResults { results } => write!(f, "pushed more than the {results} declared results"),
+        }
+    }
+}
+
+impl std::error::Error for HostError {}
+
+/// A native function bytecode can invoke with `HostCall`.
+pub struct HostFunction {
+    pub name: String,
+    /// The amount of values popped from the stack and passed as arguments.
+    pub params: u32,
+    /// The amount of values the function has to push.
+    pub results: u32,
+    pub(crate) func: Box<dyn FnMut(&mut HostContext) -> HostResult + Send>,
+}
+
+impl HostFunction {
+    pub fn new<F>(name: &str, params: u32, results: u32, func: F) -> HostFunction
+    where
+        F: FnMut(&mut HostContext) -> HostResult + Send + 'static,
+    {
+        HostFunction {
+            name: name.to_string(),
+            params,
+            results,
+            func: Box::new(func),
+        }
+    }
+}
+
+/// The view of the machine a host function gets, limited to its arguments, its results and
+/// guest memory.
+pub struct HostContext<'a> {
+    args: Vec<u32>,
+    results: Vec<u32>,
+    limit: u32,
+    memory: &'a mut [Value],
+}
+
+impl<'a> HostContext<'a> {
+    pub(crate) fn new(args: Vec<u32>, limit: u32, memory: &'a mut [Value]) -> HostContext<'a> {
+        HostContext { args, results: Vec::new(), limit, memory }
+    }
+
+    pub(crate) fn into_results(self) -> Vec<u32> {
+        self.results
+    }
+
+    /// The arguments, the first one was the deepest on the stack.
+    pub fn args(&self) -> &[u32] {
+        &self.args
+    }
+
+    /// Push a result, they are pushed onto the stack in order once the function returns.
+    pub fn push(&mut self, value: u32) -> Result<(), HostError> {
+        if self.results.len() as u32 >= self.limit {
+            return Err(HostError::TooManyResults { results: self.limit });
+        }
+
+        self.results.push(value);
+
+        Ok(())
+    }
+
+    fn range(&self, addr: u32, len: u32) -> Result<std::ops::Range<usize>, HostError> {
+        let start = addr as usize;
+        let end = start + len as usize;
+
+        if end <= self.memory.len() {
+            Ok(start..end)
+        } else {
+            Err(HostError::OutOfBounds { addr, len })
+        }
+    }
+
+    /// The value of the memory cell at `addr`.
+    pub fn load(&self, addr: u32) -> Result<u32, HostError> {
+        let range = self.range(addr, 1)?;
+
+        Ok(self.memory[range.start].as_int())
+    }
+
+    pub fn store(&mut self, addr: u32, value: u32) -> Result<(), HostError> {
+        let range = self.range(addr, 1)?;
+
+        self.memory[range.start] = Value::Int(value);
+
+        Ok(())
+    }
+
+    /// Read `len` cells starting at `addr` as bytes, one byte per cell.
+    pub fn read_bytes(&self, addr: u32, len: u32) -> Result<Vec<u8>, HostError> {
+        let range = self.range(addr, len)?;
+
+        Ok(self.memory[range]
+            .iter()
+            .map(|cell| cell.as_int().clamp(0, 255) as u8)
+            .collect())
+    }
+
+    /// Write bytes into memory starting at `addr`, one byte per cell.
+    pub fn write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<(), HostError> {
+        let range = self.range(addr, bytes.len() as u32)?;
+
+        for (cell, byte) in self.memory[range].iter_mut().zip(bytes) {
+            *cell = Value::Int(*byte as u32);
+        }
+
+        Ok(())
+    }
+}
@@ -5,8 +5,88 @@ use std::fmt;
 
 use colored::Colorize;
 
+pub mod parser;
+pub mod verify;
+pub mod optimize;
+pub mod loader;
+pub mod exec;
+pub mod host;
+pub mod linker;
+pub mod log;
+pub mod syscall;
+pub mod snapshot;
 
-#[derive(Debug)]
+/// Magic bytes at the start of a sectioned bytecode file.
+pub const MAGIC: [u8; 4] = *b"STCK";
+pub const VERSION: u8 = 1;
+
+/// The sections of a bytecode file, each one is prefixed by its id and length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Section {
+    Code,
+    Data,
+    Functions,
+    Symbols,
+    Lines,
+    Exports,
+    Imports,
+}
+
+impl Section {
+    pub fn id(&self) -> u8 {
+        match self {
+            Section::Code => 0x01,
+            Section::Data => 0x02,
+            Section::Functions => 0x03,
+            Section::Symbols => 0x04,
+            Section::Lines => 0x05,
+            Section::Exports => 0x06,
+            Section::Imports => 0x07,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Section> {
+        match id {
+            0x01 => Some(Section::Code),
+            0x02 => Some(Section::Data),
+            0x03 => Some(Section::Functions),
+            0x04 => Some(Section::Symbols),
+            0x05 => Some(Section::Lines),
+            0x06 => Some(Section::Exports),
+            0x07 => Some(Section::Imports),
+            _ => None,
+        }
+    }
+}
+
+/// Metadata of the function starting at a label, calls to it are checked against it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub label: u32,
+    pub name: String,
+    /// The amount of values the function consumes from the stack.
+    pub params: u32,
+    /// The amount of values the function leaves on the stack in place of its parameters.
+    pub results: u32,
+    /// The amount of local slots reserved when the function is called.
+    pub locals: u32,
+}
+
+/// A location in the source a program was compiled from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum ExprKind {
     Add,
     Sub,
@@ -14,7 +94,7 @@ pub enum ExprKind {
     Div,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Jump {
     Unconditional,
     Equal,
@@ -23,9 +103,13 @@ pub enum Jump {
     Lesser,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum StackOp {
     Push(u32),
+    /// Push the address the data item with this id is loaded at.
+    PushData(u32),
+    /// Push a reference to the function at this label, to be called with `CallIndirect`.
+    PushFunc(u32),
 
     Swap,
     Dump,
@@ -33,30 +117,91 @@ pub enum StackOp {
     Dup,
     Rot,
     Cmp,
+
+    /// Copy the second value to the top.
+    Over,
+    /// Remove the second value.
+    Nip,
+    /// Copy the top value below the second value.
+    Tuck,
+    /// Copy the value n below the top to the top, `Pick(0)` is `Dup`.
+    Pick(u32),
+    /// Move the value n below the top to the top, `Roll(1)` is `Swap`.
+    Roll(u32),
+    /// Push the amount of values on the stack.
+    Depth,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum MemOp {
+    /// Copy the string and a terminating NUL into memory at the popped address, strings with
+    /// an embedded NUL have to use `InsertBytes`.
     InsertStr(String),
+    /// Copy the bytes into memory at the popped address and push their length.
+    InsertBytes(Vec<u8>),
     Store,
     Load,
 }
 
-#[derive(Debug)]
+/// Operations on the local slots of the current call frame.
+#[derive(Clone, Debug)]
+pub enum LocalOp {
+    /// Reserve n zeroed local slots.
+    Enter(u32),
+    /// Release the local slots of the current frame, returning does this as well.
+    Leave,
+    Load(u32),
+    Store(u32),
+}
+
+#[derive(Clone, Debug)]
 pub enum Inst {
     BinaryExpr(ExprKind),
     StackOp(StackOp),
     MemOp(MemOp),
+    LocalOp(LocalOp),
     Jump(Jump, u32),
+    /// Jump to the label at the index popped from the stack, continues with the next
+    /// instruction if the index is out of range.
+    JumpTable(Vec<u32>),
     Call(u32),
+    /// Call the function whose reference is popped from the stack.
+    CallIndirect,
 
     Label(u32),
 
     Syscall,
+    /// Call the host function registered with this id.
+    HostCall(u32),
+    /// Write the buffered output to standard output.
+    Flush,
     Return,
     Halt,
 }
 
+impl Inst {
+    /// The labels the instruction jumps to, calls or references.
+    pub fn targets(&self) -> &[u32] {
+        match self {
+            Inst::Jump(_, label) | Inst::Call(label) | Inst::StackOp(StackOp::PushFunc(label)) => std::slice::from_ref(label),
+            Inst::JumpTable(labels) => labels,
+            _ => &[],
+        }
+    }
+
+    /// The size of the instruction in bytes once encoded.
+    pub fn size(&self) -> usize {
+        match self {
+            Inst::Label(_) | Inst::Call(_) | Inst::Jump(..) | Inst::StackOp(StackOp::Push(_) | StackOp::PushData(_) | StackOp::PushFunc(_) | StackOp::Pick(_) | StackOp::Roll(_)) => 5,
+            Inst::MemOp(MemOp::InsertStr(string)) => 1 + string.len() + usize::from(!string.ends_with('\0')),
+            Inst::MemOp(MemOp::InsertBytes(bytes)) => 5 + bytes.len(),
+            Inst::JumpTable(labels) => 5 + labels.len() * 4,
+            Inst::LocalOp(LocalOp::Enter(_) | LocalOp::Load(_) | LocalOp::Store(_)) | Inst::HostCall(_) => 5,
+            _ => 1,
+        }
+    }
+}
+
 impl fmt::Display for Inst {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -64,19 +209,43 @@ impl fmt::Display for Inst {
             Inst::BinaryExpr(op) =>   write!(fmt, "{}", format!("{:?}", *op).yellow())?,
             Inst::Label(label) =>     write!(fmt, "{:05} <{}>", "Label".yellow(), format!("{}", *label).blue())?,
             Inst::Call(addr) =>       write!(fmt, "{:05} <{}>", "Call".yellow(), format!("{}", *addr).blue())?,
+            Inst::HostCall(id) =>     write!(fmt, "{:05} ({})", "HostCall".yellow(), format!("{}", *id).blue())?,
+            Inst::JumpTable(labels) => {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|label| format!("<{}>", format!("{}", *label).blue()))
+                    .collect();
+
+                write!(fmt, "{:05} [{}]", "JumpTable".yellow(), labels.join(", "))?;
+            },
             Inst::MemOp(op) => {
                 match op {
                     MemOp::InsertStr(string) => {
                         write!(fmt, "{:05} {}", "Str".yellow(), format!("{:?}", *string).green())?;
                     },
+                    MemOp::InsertBytes(bytes) => {
+                        write!(fmt, "{:05} {}", "Bytes".yellow(), format!("\"{}\"", bytes.escape_ascii()).green())?;
+                    },
                     _ => {
                         write!(fmt, "{}", format!("{:?}", *op).yellow())?;
                     },
                 }
             },
+            Inst::LocalOp(op) => {
+                match op {
+                    LocalOp::Enter(count) => write!(fmt, "{:05} ({})", "Enter".yellow(), format!("{}", *count).blue())?,
+                    LocalOp::Load(index) => write!(fmt, "{:05} [{}]", "LoadLocal".yellow(), format!("{}", *index).blue())?,
+                    LocalOp::Store(index) => write!(fmt, "{:05} [{}]", "StoreLocal".yellow(), format!("{}", *index).blue())?,
+                    LocalOp::Leave => write!(fmt, "{}", "Leave".yellow())?,
+                }
+            },
             Inst::StackOp(op) => {
                 match op {
                     StackOp::Push(integer) => write!(fmt, "{:05} ({})", "Push".yellow(), format!("{}", *integer).blue())?,
+                    StackOp::PushData(id) => write!(fmt, "{:05} #{}", "PushData".yellow(), format!("{}", *id).blue())?,
+                    StackOp::PushFunc(label) => write!(fmt, "{:05} <{}>", "PushFunc".yellow(), format!("{}", *label).blue())?,
+                    StackOp::Pick(index) => write!(fmt, "{:05} ({})", "Pick".yellow(), format!("{}", *index).blue())?,
+                    StackOp::Roll(index) => write!(fmt, "{:05} ({})", "Roll".yellow(), format!("{}", *index).blue())?,
                     _ => write!(fmt, "{}", format!("{:?}", *op).yellow())?,
                 }
             },
@@ -89,6 +258,13 @@ impl fmt::Display for Inst {
 
 pub struct CodeGen {
     instructions: Vec<Inst>,
+    data: Vec<Vec<u8>>,
+    functions: Vec<Function>,
+    symbols: Vec<(u32, String)>,
+    /// The instruction index every span starts at, a span covers the instructions up to the next.
+    spans: Vec<(usize, Span)>,
+    exports: Vec<(u32, String)>,
+    imports: Vec<(u32, String)>,
     writer: BufWriter<File>,
 }
 
@@ -96,6 +272,12 @@ impl CodeGen {
     pub fn new(file: &str) -> Result<CodeGen, Box<dyn std::error::Error>> {
         Ok(CodeGen {
             instructions: Vec::new(),
+            data: Vec::new(),
+            functions: Vec::new(),
+            symbols: Vec::new(),
+            spans: Vec::new(),
+            exports: Vec::new(),
+            imports: Vec::new(),
             writer: BufWriter::new(File::create(file)?),
         })
     }
@@ -104,6 +286,101 @@ impl CodeGen {
         self.instructions.push(inst);
     }
 
+    /// Add an item to the data section, returns the id to use with `PushData`.
+    ///
+    /// Data is loaded into memory once before execution starts, one byte per cell.
+    pub fn append_data(&mut self, bytes: &[u8]) -> u32 {
+        self.data.push(bytes.to_vec());
+
+        self.data.len() as u32 - 1
+    }
+
+    /// Append an instruction that was generated from the source at `span`.
+    ///
+    /// Instructions appended without a span afterwards belong to the same span.
+    pub fn append_spanned(&mut self, inst: Inst, span: Span) {
+        if self.spans.last().map(|(_, last)| *last != span).unwrap_or(true) {
+            self.spans.push((self.instructions.len(), span));
+        }
+
+        self.instructions.push(inst);
+    }
+
+    /// Append a label and record its name in the symbol section.
+    pub fn append_label_named(&mut self, label: u32, name: &str) {
+        self.instructions.push(Inst::Label(label));
+        self.name_label(label, name);
+    }
+
+    /// Record the name of a label in the symbol section, the last name of a label wins.
+    pub fn name_label(&mut self, label: u32, name: &str) {
+        self.symbols.retain(|(other, _)| *other != label);
+        self.symbols.push((label, name.to_string()));
+    }
+
+    /// Drop the symbol section and the line table, labels are only known by their number and
+    /// instructions by their index afterwards.
+    pub fn strip(&mut self) {
+        self.symbols.clear();
+        self.spans.clear();
+    }
+
+    /// Make a label defined in this object available to other objects under `name`.
+    pub fn export(&mut self, label: u32, name: &str) {
+        self.exports.push((label, name.to_string()));
+    }
+
+    /// Bind a label used but not defined in this object to the label another object exports
+    /// under `name`, the linker resolves it.
+    pub fn import(&mut self, label: u32, name: &str) {
+        self.imports.push((label, name.to_string()));
+    }
+
+    /// Add the metadata of the function starting at `function.label` to the function table.
+    pub fn define_function(&mut self, function: Function) {
+        self.functions.push(function);
+    }
+
+    /// The amount of instructions appended so far.
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    /// Run the peephole optimizer over the appended instructions, every instruction that is
+    /// kept keeps its span.
+    pub fn optimize(&mut self) {
+        let roots: Vec<u32> = self.functions
+            .iter()
+            .map(|function| function.label)
+            .chain(self.exports.iter().map(|(label, _)| *label))
+            .chain(self.imports.iter().map(|(label, _)| *label))
+            .collect();
+
+        let spans = std::mem::take(&mut self.spans);
+        let mut starts = spans.iter().enumerate().peekable();
+        let mut current = None;
+        let mut instructions: Vec<optimize::Spanned> = Vec::with_capacity(self.instructions.len());
+
+        for (index, inst) in std::mem::take(&mut self.instructions).into_iter().enumerate() {
+            while let Some((span, _)) = starts.next_if(|(_, (start, _))| *start <= index) {
+                current = Some(span);
+            }
+
+            instructions.push((inst, current));
+        }
+
+        for (inst, span) in optimize::optimize_spanned(instructions, &roots) {
+            match span {
+                Some(span) => self.append_spanned(inst, spans[span].1.clone()),
+                None => self.append(inst),
+            }
+        }
+    }
+
     fn output_int(&self, integer: u32) -> [u8; 4] {
         if cfg!(target_endian = "big") {
             integer.to_be_bytes()
@@ -112,21 +389,152 @@ impl CodeGen {
         }
     }
 
+    /// Encode a list of named labels as a u32 count followed by the label and name of each.
+    fn output_names(&self, names: &[(u32, String)]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        bytes.write_all(&self.output_int(names.len() as u32))?;
+
+        for (label, name) in names {
+            bytes.write_all(&self.output_int(*label))?;
+            bytes.write_all(&self.output_int(name.len() as u32))?;
+            bytes.write_all(name.as_bytes())?;
+        }
+
+        Ok(bytes)
+    }
+
+    fn output_section(&mut self, section: Section, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.writer.write_all(&[section.id()])?;
+
+        self.writer.write_all(&self.output_int(bytes.len() as u32))?;
+
+        self.writer.write_all(bytes)?;
+
+        Ok(())
+    }
+
     pub fn output(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        for inst in &self.instructions {
+        let code = self.output_code()?;
+
+        let mut data: Vec<u8> = Vec::new();
+
+        data.write_all(&self.output_int(self.data.len() as u32))?;
+
+        for item in &self.data {
+            data.write_all(&self.output_int(item.len() as u32))?;
+
+            data.write_all(item)?;
+        }
+
+        let mut functions: Vec<u8> = Vec::new();
+
+        functions.write_all(&self.output_int(self.functions.len() as u32))?;
+
+        for function in &self.functions {
+            functions.write_all(&self.output_int(function.label))?;
+            functions.write_all(&self.output_int(function.name.len() as u32))?;
+            functions.write_all(function.name.as_bytes())?;
+            functions.write_all(&self.output_int(function.params))?;
+            functions.write_all(&self.output_int(function.results))?;
+            functions.write_all(&self.output_int(function.locals))?;
+        }
+
+        let symbols = self.output_names(&self.symbols)?;
+        let exports = self.output_names(&self.exports)?;
+        let imports = self.output_names(&self.imports)?;
+
+        let mut files: Vec<&str> = Vec::new();
+        let mut lines: Vec<u8> = Vec::new();
+
+        for (_, span) in &self.spans {
+            if !files.contains(&span.file.as_str()) {
+                files.push(&span.file);
+            }
+        }
+
+        lines.write_all(&self.output_int(files.len() as u32))?;
+
+        for file in &files {
+            lines.write_all(&self.output_int(file.len() as u32))?;
+            lines.write_all(file.as_bytes())?;
+        }
+
+        lines.write_all(&self.output_int(self.spans.len() as u32))?;
+
+        for (index, span) in &self.spans {
+            let file = files.iter().position(|file| *file == span.file).unwrap_or(0);
+
+            lines.write_all(&self.output_int(*index as u32))?;
+            lines.write_all(&self.output_int(file as u32))?;
+            lines.write_all(&self.output_int(span.line))?;
+            lines.write_all(&self.output_int(span.column))?;
+        }
+
+        self.writer.write_all(&MAGIC)?;
+
+        self.writer.write_all(&[VERSION])?;
+
+        self.output_section(Section::Code, &code)?;
+
+        if !self.data.is_empty() {
+            self.output_section(Section::Data, &data)?;
+        }
+
+        if !self.functions.is_empty() {
+            self.output_section(Section::Functions, &functions)?;
+        }
+
+        if !self.symbols.is_empty() {
+            self.output_section(Section::Symbols, &symbols)?;
+        }
+
+        if !self.spans.is_empty() {
+            self.output_section(Section::Lines, &lines)?;
+        }
+
+        if !self.exports.is_empty() {
+            self.output_section(Section::Exports, &exports)?;
+        }
+
+        if !self.imports.is_empty() {
+            self.output_section(Section::Imports, &imports)?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    fn output_code(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut code: Vec<u8> = Vec::new();
+
+        for (ip, inst) in self.instructions.iter().enumerate() {
             match inst {
                 Inst::Label(ident) => {
-                    self.writer.write_all(&[0x4C])?;
+                    code.write_all(&[0x4C])?;
 
-                    self.writer.write_all(&self.output_int(*ident))?;
+                    code.write_all(&self.output_int(*ident))?;
                 },
                 Inst::Call(addr) => {
-                    self.writer.write_all(&[0x2F])?;
+                    code.write_all(&[0x2F])?;
 
-                    self.writer.write_all(&self.output_int(*addr))?;
+                    code.write_all(&self.output_int(*addr))?;
+                },
+                Inst::CallIndirect => {
+                    code.write_all(&[0x2E])?;
+                },
+                Inst::JumpTable(labels) => {
+                    code.write_all(&[0x6F])?;
+
+                    code.write_all(&self.output_int(labels.len() as u32))?;
+
+                    for label in labels {
+                        code.write_all(&self.output_int(*label))?;
+                    }
                 },
                 Inst::Jump(condition, addr) => {
-                    self.writer.write_all(&[
+                    code.write_all(&[
                         match condition {
                             Jump::Unconditional => 0x6A,
                             Jump::Equal =>         0x6B,
@@ -136,52 +544,122 @@ impl CodeGen {
                         }
                     ])?;
 
-                    self.writer.write_all(&self.output_int(*addr))?;
+                    code.write_all(&self.output_int(*addr))?;
                 },
                 Inst::StackOp(op) => {
                     match op {
                         StackOp::Push(integer) => {
-                            self.writer.write_all(&[0x01])?;
+                            code.write_all(&[0x01])?;
+
+                            code.write_all(&self.output_int(*integer))?;
+                        },
+                        StackOp::PushData(id) => {
+                            code.write_all(&[0x08])?;
+
+                            code.write_all(&self.output_int(*id))?;
+                        },
+                        StackOp::PushFunc(label) => {
+                            code.write_all(&[0x10])?;
 
-                            self.writer.write_all(&self.output_int(*integer))?;
+                            code.write_all(&self.output_int(*label))?;
                         },
                         StackOp::Pop => {
-                            self.writer.write_all(&[0x02])?;
+                            code.write_all(&[0x02])?;
                         },
                         StackOp::Dup => {
-                            self.writer.write_all(&[0x05])?;
+                            code.write_all(&[0x05])?;
                         },
                         StackOp::Swap => {
-                            self.writer.write_all(&[0x06])?;
+                            code.write_all(&[0x06])?;
                         },
                         StackOp::Rot => {
-                            self.writer.write_all(&[0x07])?;
+                            code.write_all(&[0x07])?;
                         },
                         StackOp::Dump => {
-                            self.writer.write_all(&[0x03])?;
+                            code.write_all(&[0x03])?;
                         },
                         StackOp::Cmp => {
-                            self.writer.write_all(&[0x43])?;
+                            code.write_all(&[0x43])?;
+                        },
+                        StackOp::Over => {
+                            code.write_all(&[0x09])?;
+                        },
+                        StackOp::Nip => {
+                            code.write_all(&[0x0A])?;
+                        },
+                        StackOp::Tuck => {
+                            code.write_all(&[0x0B])?;
+                        },
+                        StackOp::Pick(index) => {
+                            code.write_all(&[0x0C])?;
+
+                            code.write_all(&self.output_int(*index))?;
+                        },
+                        StackOp::Roll(index) => {
+                            code.write_all(&[0x0E])?;
+
+                            code.write_all(&self.output_int(*index))?;
+                        },
+                        StackOp::Depth => {
+                            code.write_all(&[0x0F])?;
                         },
                     }
                 },
                 Inst::MemOp(op) => {
                     match op {
                         MemOp::InsertStr(string) => {
-                            self.writer.write_all(&[0x8C])?;
+                            // The string would end at the first NUL once it is parsed again.
+                            if string.strip_suffix('\0').unwrap_or(string).contains('\0') {
+                                return Err(format!("string at instruction {ip} contains a NUL byte, use InsertBytes instead").into());
+                            }
+
+                            code.write_all(&[0x8C])?;
+
+                            code.write_all(string.as_bytes())?;
 
-                            self.writer.write_all(string.as_bytes())?;
+                            if !string.ends_with('\0') {
+                                code.write_all(&[0])?;
+                            }
+                        },
+                        MemOp::InsertBytes(bytes) => {
+                            code.write_all(&[0x8D])?;
+
+                            code.write_all(&self.output_int(bytes.len() as u32))?;
+
+                            code.write_all(bytes)?;
                         },
                         MemOp::Load => {
-                            self.writer.write_all(&[0x8A])?;
+                            code.write_all(&[0x8A])?;
                         },
                         MemOp::Store => {
-                            self.writer.write_all(&[0x8B])?;
+                            code.write_all(&[0x8B])?;
+                        },
+                    }
+                },
+                Inst::LocalOp(op) => {
+                    match op {
+                        LocalOp::Enter(count) => {
+                            code.write_all(&[0x30])?;
+
+                            code.write_all(&self.output_int(*count))?;
+                        },
+                        LocalOp::Leave => {
+                            code.write_all(&[0x31])?;
+                        },
+                        LocalOp::Load(index) => {
+                            code.write_all(&[0x32])?;
+
+                            code.write_all(&self.output_int(*index))?;
+                        },
+                        LocalOp::Store(index) => {
+                            code.write_all(&[0x33])?;
+
+                            code.write_all(&self.output_int(*index))?;
                         },
                     }
                 },
                 Inst::BinaryExpr(kind) => {
-                    self.writer.write_all(&[
+                    code.write_all(&[
                         match kind {
                             ExprKind::Add => 0x28,
                             ExprKind::Sub => 0x29,
@@ -191,21 +669,50 @@ impl CodeGen {
                     ])?;
                 },
                 Inst::Syscall => {
-                    self.writer.write_all(&[0x53])?;
+                    code.write_all(&[0x53])?;
+                },
+                Inst::HostCall(id) => {
+                    code.write_all(&[0x54])?;
+
+                    code.write_all(&self.output_int(*id))?;
+                },
+                Inst::Flush => {
+                    code.write_all(&[0x55])?;
                 },
                 Inst::Return => {
-                    self.writer.write_all(&[0x0D])?;
+                    code.write_all(&[0x0D])?;
                 },
                 Inst::Halt => {
-                    self.writer.write_all(&[0x04])?;
+                    code.write_all(&[0x04])?;
                 },
             }
         }
 
-        self.writer.flush()?;
-
-        Ok(())
+        Ok(code)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_with_an_embedded_nul_are_rejected() {
+        let path = std::env::temp_dir().join(format!("stacked-codegen-{}.stck", std::process::id()));
+        let output = |string: &str| {
+            let mut codegen = CodeGen::new(path.to_str().unwrap()).unwrap();
+
+            codegen.append(Inst::MemOp(MemOp::InsertStr(string.to_string())));
+            codegen.output()
+        };
 
+        assert!(output("text").is_ok());
+        assert!(output("text\0").is_ok());
+
+        let err = output("te\0xt").unwrap_err();
+
+        assert!(err.to_string().contains("InsertBytes"), "{err}");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{CodeGen, Function, Inst, Span, StackOp, parser::Parser};
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    /// An imported symbol that no object exports.
+    Undefined { object: String, name: String },
+    /// A symbol exported by more than one object.
+    Duplicate { name: String, first: String, second: String },
+    /// A label that is used but neither defined nor imported.
+    UnknownLabel { object: String, label: u32 },
+    /// A label defined more than once in the same object.
+    DuplicateLabel { object: String, label: u32 },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Undefined { object, name } => write!(f, "{object}: undefined symbol `{name}`"),
+            LinkError::Duplicate { name, first, second } => write!(f, "{second}: symbol `{name}` is already exported by {first}"),
+            LinkError::UnknownLabel { object, label } => write!(f, "{object}: label `{label}` is neither defined nor imported"),
+            LinkError::DuplicateLabel { object, label } => write!(f, "{object}: label `{label}` is defined more than once"),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// A parsed relocatable object, its labels and data ids are local to it.
+#[derive(Clone, Debug, Default)]
+pub struct Object {
+    /// The name errors refer to the object by, usually its path.
+    pub name: String,
+    pub instructions: Vec<Inst>,
+    pub data: Vec<Vec<u8>>,
+    pub functions: Vec<Function>,
+    pub symbols: HashMap<u32, String>,
+    pub lines: Vec<(usize, Span)>,
+    pub exports: Vec<(u32, String)>,
+    pub imports: Vec<(u32, String)>,
+}
+
+impl Object {
+    pub fn new(name: &str, parser: Parser, instructions: Vec<Inst>) -> Object {
+        Object {
+            name: name.to_string(),
+            instructions,
+            data: parser.data,
+            functions: parser.functions,
+            symbols: parser.symbols,
+            lines: parser.lines,
+            exports: parser.exports,
+            imports: parser.imports,
+        }
+    }
+
+    /// Append every section of the object to `codegen`.
+    pub fn emit(self, codegen: &mut CodeGen) {
+        let mut lines = self.lines.into_iter().peekable();
+
+        for (index, inst) in self.instructions.into_iter().enumerate() {
+            let mut span = None;
+
+            while let Some((_, next)) = lines.next_if(|(start, _)| *start <= index) {
+                span = Some(next);
+            }
+
+            match span {
+                Some(span) => codegen.append_spanned(inst, span),
+                None => codegen.append(inst),
+            }
+        }
+
+        for item in &self.data {
+            codegen.append_data(item);
+        }
+
+        for function in self.functions {
+            codegen.define_function(function);
+        }
+
+        for (label, name) in &self.symbols {
+            codegen.name_label(*label, name);
+        }
+
+        for (label, name) in &self.exports {
+            codegen.export(*label, name);
+        }
+
+        for (label, name) in &self.imports {
+            codegen.import(*label, name);
+        }
+    }
+}
+
+/// Rewrite every label and data id of an instruction.
+fn relocate(inst: Inst, labels: &HashMap<u32, u32>, data_base: u32) -> Inst {
+    let label = |label: u32| labels[&label];
+
+    match inst {
+        Inst::Label(id) => Inst::Label(label(id)),
+        Inst::Jump(jump, id) => Inst::Jump(jump, label(id)),
+        Inst::JumpTable(ids) => Inst::JumpTable(ids.into_iter().map(label).collect()),
+        Inst::Call(id) => Inst::Call(label(id)),
+        Inst::StackOp(StackOp::PushFunc(id)) => Inst::StackOp(StackOp::PushFunc(label(id))),
+        Inst::StackOp(StackOp::PushData(id)) => Inst::StackOp(StackOp::PushData(data_base + id)),
+        inst => inst,
+    }
+}
+
+/// Merge relocatable objects into one program.
+///
+/// Every label defined in an object gets a fresh number, imported labels are bound to the label
+/// exported under the same name and data ids are shifted past the data of the preceding objects.
+/// Execution starts at the first instruction of the first object. The exports are kept so the
+/// result can be linked again.
+pub fn link(objects: Vec<Object>) -> Result<Object, Vec<LinkError>> {
+    let mut errors: Vec<LinkError> = Vec::new();
+    let mut relocations: Vec<HashMap<u32, u32>> = Vec::with_capacity(objects.len());
+    let mut exports: HashMap<&str, (u32, &str)> = HashMap::new();
+    let mut next: u32 = 0;
+
+    for object in &objects {
+        let mut labels: HashMap<u32, u32> = HashMap::new();
+
+        for inst in &object.instructions {
+            if let Inst::Label(label) = inst {
+                if labels.contains_key(label) {
+                    let err = LinkError::DuplicateLabel { object: object.name.clone(), label: *label };
+
+                    if !errors.contains(&err) {
+                        errors.push(err);
+                    }
+                } else {
+                    labels.insert(*label, next);
+                    next += 1;
+                }
+            }
+        }
+
+        for (label, name) in &object.exports {
+            let Some(global) = labels.get(label) else {
+                errors.push(LinkError::UnknownLabel { object: object.name.clone(), label: *label });
+                continue;
+            };
+
+            if let Some((_, first)) = exports.get(name.as_str()) {
+                errors.push(LinkError::Duplicate { name: name.clone(), first: first.to_string(), second: object.name.clone() });
+            } else {
+                exports.insert(name, (*global, &object.name));
+            }
+        }
+
+        relocations.push(labels);
+    }
+
+    for (object, labels) in objects.iter().zip(relocations.iter_mut()) {
+        for (label, name) in &object.imports {
+            if labels.contains_key(label) {
+                continue;
+            }
+
+            match exports.get(name.as_str()) {
+                Some((global, _)) => {
+                    labels.insert(*label, *global);
+                },
+                None => errors.push(LinkError::Undefined { object: object.name.clone(), name: name.clone() }),
+            }
+        }
+
+        let mut unknown: Vec<u32> = object.instructions
+            .iter()
+            .flat_map(Inst::targets)
+            .chain(object.functions.iter().map(|function| &function.label))
+            .filter(|label| !labels.contains_key(label))
+            .copied()
+            .collect();
+
+        unknown.sort();
+        unknown.dedup();
+
+        for label in unknown {
+            if !object.imports.iter().any(|(import, _)| *import == label) {
+                errors.push(LinkError::UnknownLabel { object: object.name.clone(), label });
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut linked = Object {
+        name: objects
+            .iter()
+            .map(|object| object.name.as_str())
+            .collect::<Vec<&str>>()
+            .join("+"),
+        ..Object::default()
+    };
+
+    for (object, labels) in objects.into_iter().zip(relocations) {
+        let data_base = linked.data.len() as u32;
+        let code_base = linked.instructions.len();
+
+        linked.exports.extend(object.exports.into_iter().map(|(label, name)| (labels[&label], name)));
+        linked.symbols.extend(object.symbols.into_iter().filter_map(|(label, name)| Some((*labels.get(&label)?, name))));
+        linked.lines.extend(object.lines.into_iter().map(|(index, span)| (code_base + index, span)));
+        linked.functions.extend(object.functions.into_iter().map(|function| Function { label: labels[&function.label], ..function }));
+        linked.instructions.extend(object.instructions.into_iter().map(|inst| relocate(inst, &labels, data_base)));
+        linked.data.extend(object.data);
+    }
+
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(name: &str, instructions: Vec<Inst>) -> Object {
+        Object { name: name.to_string(), instructions, ..Object::default() }
+    }
+
+    #[test]
+    fn labels_are_renumbered_per_object() {
+        let linked = link(vec![
+            object("a", vec![Inst::Label(0), Inst::Jump(crate::Jump::Unconditional, 0)]),
+            object("b", vec![Inst::Label(0), Inst::Call(0)]),
+        ]).unwrap();
+
+        let labels: Vec<u32> = linked.instructions
+            .iter()
+            .flat_map(|inst| match inst {
+                Inst::Label(label) => vec![*label],
+                inst => inst.targets().to_vec(),
+            })
+            .collect();
+
+        assert_eq!(labels, vec![0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn duplicate_labels_in_one_object() {
+        let errors = link(vec![
+            object("a", vec![Inst::Label(0), Inst::Halt]),
+            object("b", vec![Inst::Label(0), Inst::Label(1), Inst::Label(0), Inst::Label(0)]),
+        ]).unwrap_err();
+
+        assert_eq!(errors, vec![LinkError::DuplicateLabel { object: "b".into(), label: 0 }]);
+    }
+}
@@ -0,0 +1,464 @@
+use crate::{Inst, ExprKind, Jump, StackOp, MemOp, LocalOp, Function, Span, exec::MEMORY_SIZE, parser::Parser};
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum LoadError {
+    UnknownLabel { ip: usize, label: u32 },
+    UnknownData { ip: usize, id: u32 },
+    DataTooLarge { size: usize },
+    UnknownFunction { label: u32, name: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::UnknownLabel { ip, label } => write!(f, "instruction {ip}: unknown label `{label}`"),
+            LoadError::UnknownData { ip, id } => write!(f, "instruction {ip}: unknown data item #{id}"),
+            LoadError::DataTooLarge { size } => write!(f, "data section of {size} bytes does not fit in memory"),
+            LoadError::UnknownFunction { label, name } => write!(f, "function `{name}`: unknown label `{label}`"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpCode {
+    Push,
+    Pop,
+    Dup,
+    Swap,
+    Rot,
+    Dump,
+    Cmp,
+    Over,
+    Nip,
+    Tuck,
+    Pick,
+    Roll,
+    Depth,
+    PushFunc,
+
+    Add,
+    Sub,
+    Mul,
+    Div,
+
+    Jump,
+    JumpEqual,
+    JumpNotEqual,
+    JumpGreater,
+    JumpLesser,
+    JumpTable,
+    Call,
+    CallIndirect,
+    /// A call to a function of the function table, checked against its signature.
+    CallFunction,
+
+    InsertStr,
+    InsertBytes,
+    Load,
+    Store,
+
+    Enter,
+    Leave,
+    LoadLocal,
+    StoreLocal,
+
+    Syscall,
+    HostCall,
+    Flush,
+    Return,
+    Halt,
+}
+
+/// A decoded instruction, the meaning of `arg` depends on the opcode.
+///
+/// Jumps and calls carry the index of the target instruction, `CallFunction` the index of the
+/// function in [`Program::functions`], `JumpTable` the index of its targets in
+/// [`Program::tables`], `InsertStr` and `InsertBytes` the index of their bytes in the
+/// constant pool, `Pick` and `Roll` the depth, local operations the slot or amount of slots,
+/// `PushFunc` the label, `HostCall` the host function id and `Push` the value itself.
+#[derive(Clone, Copy, Debug)]
+pub struct Op {
+    pub code: OpCode,
+    pub arg: u32,
+}
+
+impl Op {
+    fn new(code: OpCode) -> Op {
+        Op { code, arg: 0 }
+    }
+
+    fn with_arg(code: OpCode, arg: u32) -> Op {
+        Op { code, arg }
+    }
+}
+
+/// An entry of the function table with its label resolved.
+#[derive(Clone, Debug)]
+pub struct Callable {
+    /// The index of the first instruction of the function.
+    pub target: u32,
+    pub function: Function,
+}
+
+/// A program ready to be executed.
+///
+/// Labels are stripped from the instruction stream and every jump and call target is the index
+/// of the instruction it continues at. Machines never modify a program, so it can be parsed
+/// once and shared through an `Arc` by any amount of machines on any amount of threads.
+#[derive(Debug)]
+pub struct Program {
+    pub code: Vec<Op>,
+    /// Constant pool holding the bytes of every string and byte literal.
+    pub strings: Vec<Vec<u8>>,
+    /// Label to the index of the instruction following it.
+    pub symbols: HashMap<u32, u32>,
+    /// Byte offset of every instruction in the original program.
+    pub offsets: Vec<usize>,
+    /// The data section, loaded into memory at `data_base` before execution.
+    pub data: Vec<u8>,
+    pub data_base: u32,
+    /// The function table, calls to these functions are checked against their signature.
+    pub functions: Vec<Callable>,
+    /// Label to the index of its entry in the function table.
+    pub declared: HashMap<u32, u32>,
+    /// The targets of every jump table.
+    pub tables: Vec<Vec<u32>>,
+    /// Label to the name given to it in the symbol section.
+    pub names: HashMap<u32, String>,
+    /// The index of the first instruction of every span of the line table.
+    pub lines: Vec<(u32, Span)>,
+}
+
+impl Program {
+    /// Parse and load the bytecode file at `path`.
+    pub fn from_file(path: &str) -> Result<Program, Box<dyn std::error::Error>> {
+        Program::parse(Parser::new(path)?)
+    }
+
+    /// Parse and load bytecode that is already in memory.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Program, Box<dyn std::error::Error>> {
+        Program::parse(Parser::from_bytes(bytes))
+    }
+
+    fn parse(mut parser: Parser) -> Result<Program, Box<dyn std::error::Error>> {
+        let instructions = parser.parse()?;
+
+        Ok(load(instructions, parser.data, parser.functions, parser.symbols, parser.lines)?)
+    }
+
+    /// Find the label that resolves to the instruction at `index`.
+    pub fn label_at(&self, index: u32) -> Option<u32> {
+        self.symbols
+            .iter()
+            .filter(|(_, target)| **target == index)
+            .map(|(label, _)| *label)
+            .min()
+    }
+
+    /// The name of the function or named label starting at the instruction at `index`.
+    pub fn name_at(&self, index: u32) -> Option<&str> {
+        self.functions
+            .iter()
+            .find(|callable| callable.target == index)
+            .map(|callable| callable.function.name.as_str())
+            .or_else(|| {
+                self.symbols
+                    .iter()
+                    .filter(|(_, target)| **target == index)
+                    .filter_map(|(label, _)| self.names.get(label).map(|name| (*label, name)))
+                    .min()
+                    .map(|(_, name)| name.as_str())
+            })
+    }
+
+    /// The byte offset of the instruction at `index` in the original program.
+    pub fn offset(&self, index: u32) -> usize {
+        self.offsets
+            .get(index as usize)
+            .copied()
+            .unwrap_or_else(|| self.offsets.last().copied().unwrap_or(0))
+    }
+
+    /// The source location the instruction at `index` was generated from.
+    pub fn span_at(&self, index: u32) -> Option<&Span> {
+        let end = self.lines.partition_point(|(start, _)| *start <= index);
+
+        end.checked_sub(1).map(|last| &self.lines[last].1)
+    }
+
+    /// Decode the instruction at `index` back into an [`Inst`], jump and call targets are
+    /// mapped back to their labels.
+    pub fn inst(&self, index: u32) -> Option<Inst> {
+        let op = self.code.get(index as usize)?;
+        let label = |target: u32| self.label_at(target).unwrap_or(target);
+
+        Some(match op.code {
+            OpCode::Push => Inst::StackOp(StackOp::Push(op.arg)),
+            OpCode::Pop => Inst::StackOp(StackOp::Pop),
+            OpCode::Dup => Inst::StackOp(StackOp::Dup),
+            OpCode::Swap => Inst::StackOp(StackOp::Swap),
+            OpCode::Rot => Inst::StackOp(StackOp::Rot),
+            OpCode::Dump => Inst::StackOp(StackOp::Dump),
+            OpCode::Cmp => Inst::StackOp(StackOp::Cmp),
+            OpCode::Over => Inst::StackOp(StackOp::Over),
+            OpCode::Nip => Inst::StackOp(StackOp::Nip),
+            OpCode::Tuck => Inst::StackOp(StackOp::Tuck),
+            OpCode::Pick => Inst::StackOp(StackOp::Pick(op.arg)),
+            OpCode::Roll => Inst::StackOp(StackOp::Roll(op.arg)),
+            OpCode::Depth => Inst::StackOp(StackOp::Depth),
+            OpCode::PushFunc => Inst::StackOp(StackOp::PushFunc(op.arg)),
+
+            OpCode::Add => Inst::BinaryExpr(ExprKind::Add),
+            OpCode::Sub => Inst::BinaryExpr(ExprKind::Sub),
+            OpCode::Mul => Inst::BinaryExpr(ExprKind::Mul),
+            OpCode::Div => Inst::BinaryExpr(ExprKind::Div),
+
+            OpCode::Jump => Inst::Jump(Jump::Unconditional, label(op.arg)),
+            OpCode::JumpEqual => Inst::Jump(Jump::Equal, label(op.arg)),
+            OpCode::JumpNotEqual => Inst::Jump(Jump::NotEqual, label(op.arg)),
+            OpCode::JumpGreater => Inst::Jump(Jump::Greater, label(op.arg)),
+            OpCode::JumpLesser => Inst::Jump(Jump::Lesser, label(op.arg)),
+            OpCode::JumpTable => Inst::JumpTable(self.tables[op.arg as usize].iter().map(|target| label(*target)).collect()),
+            OpCode::Call => Inst::Call(label(op.arg)),
+            OpCode::CallIndirect => Inst::CallIndirect,
+            OpCode::CallFunction => Inst::Call(label(self.functions[op.arg as usize].target)),
+
+            OpCode::InsertStr => Inst::MemOp(MemOp::InsertStr(String::from_utf8_lossy(&self.strings[op.arg as usize]).into_owned())),
+            OpCode::InsertBytes => Inst::MemOp(MemOp::InsertBytes(self.strings[op.arg as usize].clone())),
+            OpCode::Load => Inst::MemOp(MemOp::Load),
+            OpCode::Store => Inst::MemOp(MemOp::Store),
+
+            OpCode::Enter => Inst::LocalOp(LocalOp::Enter(op.arg)),
+            OpCode::Leave => Inst::LocalOp(LocalOp::Leave),
+            OpCode::LoadLocal => Inst::LocalOp(LocalOp::Load(op.arg)),
+            OpCode::StoreLocal => Inst::LocalOp(LocalOp::Store(op.arg)),
+
+            OpCode::Syscall => Inst::Syscall,
+            OpCode::HostCall => Inst::HostCall(op.arg),
+            OpCode::Flush => Inst::Flush,
+            OpCode::Return => Inst::Return,
+            OpCode::Halt => Inst::Halt,
+        })
+    }
+}
+
+/// Resolve the labels of a parsed program and decode it into a flat array of [`Op`].
+///
+/// The data items are laid out back to back at the top of memory and every `PushData` is
+/// replaced by a push of the address its item is loaded at. Calls to labels of the function
+/// table become `CallFunction`, if a label is declared more than once the last entry wins.
+/// The names of the symbol section and the spans of the line table are kept for diagnostics.
+pub fn load(
+    instructions: Vec<Inst>,
+    data: Vec<Vec<u8>>,
+    table: Vec<Function>,
+    names: HashMap<u32, String>,
+    lines: Vec<(usize, Span)>,
+) -> Result<Program, LoadError> {
+    let size: usize = data.iter().map(|item| item.len()).sum();
+
+    if size > MEMORY_SIZE {
+        return Err(LoadError::DataTooLarge { size });
+    }
+
+    let data_base = (MEMORY_SIZE - size) as u32;
+
+    let addresses: Vec<u32> = data
+        .iter()
+        .scan(data_base, |addr, item| {
+            let start = *addr;
+            *addr += item.len() as u32;
+            Some(start)
+        })
+        .collect();
+
+    let mut symbols: HashMap<u32, u32> = HashMap::new();
+    let mut offsets: Vec<usize> = Vec::new();
+    let mut positions: Vec<u32> = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+
+    for inst in &instructions {
+        positions.push(offsets.len() as u32);
+
+        if let Inst::Label(label) = inst {
+            symbols.insert(*label, offsets.len() as u32);
+        } else {
+            offsets.push(offset);
+        }
+
+        offset += inst.size();
+    }
+
+    positions.push(offsets.len() as u32);
+    offsets.push(offset);
+
+    let lines: Vec<(u32, Span)> = lines
+        .into_iter()
+        .map(|(index, span)| (positions[index.min(instructions.len())], span))
+        .collect();
+
+    let mut functions: Vec<Callable> = Vec::with_capacity(table.len());
+    let mut declared: HashMap<u32, u32> = HashMap::new();
+
+    for function in table {
+        let target = symbols
+            .get(&function.label)
+            .copied()
+            .ok_or_else(|| LoadError::UnknownFunction { label: function.label, name: function.name.clone() })?;
+
+        declared.insert(function.label, functions.len() as u32);
+        functions.push(Callable { target, function });
+    }
+
+    let mut code: Vec<Op> = Vec::with_capacity(offsets.len());
+    let mut strings: Vec<Vec<u8>> = Vec::new();
+    let mut tables: Vec<Vec<u32>> = Vec::new();
+
+    for (ip, inst) in instructions.into_iter().enumerate() {
+        let resolve = |label: u32| symbols.get(&label).copied().ok_or(LoadError::UnknownLabel { ip, label });
+
+        code.push(match inst {
+            Inst::Label(_) => continue,
+
+            Inst::StackOp(op) => match op {
+                StackOp::Push(integer) => Op::with_arg(OpCode::Push, integer),
+                StackOp::PushData(id) => Op::with_arg(
+                    OpCode::Push,
+                    addresses.get(id as usize).copied().ok_or(LoadError::UnknownData { ip, id })?,
+                ),
+                StackOp::Pop => Op::new(OpCode::Pop),
+                StackOp::Dup => Op::new(OpCode::Dup),
+                StackOp::Swap => Op::new(OpCode::Swap),
+                StackOp::Rot => Op::new(OpCode::Rot),
+                StackOp::Dump => Op::new(OpCode::Dump),
+                StackOp::Cmp => Op::new(OpCode::Cmp),
+                StackOp::Over => Op::new(OpCode::Over),
+                StackOp::Nip => Op::new(OpCode::Nip),
+                StackOp::Tuck => Op::new(OpCode::Tuck),
+                StackOp::Pick(index) => Op::with_arg(OpCode::Pick, index),
+                StackOp::Roll(index) => Op::with_arg(OpCode::Roll, index),
+                StackOp::Depth => Op::new(OpCode::Depth),
+                StackOp::PushFunc(label) => {
+                    resolve(label)?;
+
+                    Op::with_arg(OpCode::PushFunc, label)
+                },
+            },
+
+            Inst::BinaryExpr(kind) => Op::new(match kind {
+                ExprKind::Add => OpCode::Add,
+                ExprKind::Sub => OpCode::Sub,
+                ExprKind::Mul => OpCode::Mul,
+                ExprKind::Div => OpCode::Div,
+            }),
+
+            Inst::Jump(jump, label) => Op::with_arg(
+                match jump {
+                    Jump::Unconditional => OpCode::Jump,
+                    Jump::Equal => OpCode::JumpEqual,
+                    Jump::NotEqual => OpCode::JumpNotEqual,
+                    Jump::Greater => OpCode::JumpGreater,
+                    Jump::Lesser => OpCode::JumpLesser,
+                },
+                resolve(label)?,
+            ),
+            Inst::Call(label) => match declared.get(&label) {
+                Some(index) => Op::with_arg(OpCode::CallFunction, *index),
+                None => Op::with_arg(OpCode::Call, resolve(label)?),
+            },
+            Inst::JumpTable(labels) => {
+                tables.push(labels.into_iter().map(resolve).collect::<Result<Vec<u32>, LoadError>>()?);
+
+                Op::with_arg(OpCode::JumpTable, tables.len() as u32 - 1)
+            },
+            Inst::CallIndirect => Op::new(OpCode::CallIndirect),
+
+            Inst::MemOp(op) => match op {
+                MemOp::InsertStr(string) => {
+                    let mut bytes = string.into_bytes();
+
+                    // The parser keeps the terminating NUL, strings built in memory may lack it.
+                    if bytes.last() != Some(&0) {
+                        bytes.push(0);
+                    }
+
+                    strings.push(bytes);
+
+                    Op::with_arg(OpCode::InsertStr, strings.len() as u32 - 1)
+                },
+                MemOp::InsertBytes(bytes) => {
+                    strings.push(bytes);
+
+                    Op::with_arg(OpCode::InsertBytes, strings.len() as u32 - 1)
+                },
+                MemOp::Load => Op::new(OpCode::Load),
+                MemOp::Store => Op::new(OpCode::Store),
+            },
+
+            Inst::LocalOp(op) => match op {
+                LocalOp::Enter(count) => Op::with_arg(OpCode::Enter, count),
+                LocalOp::Leave => Op::new(OpCode::Leave),
+                LocalOp::Load(index) => Op::with_arg(OpCode::LoadLocal, index),
+                LocalOp::Store(index) => Op::with_arg(OpCode::StoreLocal, index),
+            },
+
+            Inst::Syscall => Op::new(OpCode::Syscall),
+            Inst::HostCall(id) => Op::with_arg(OpCode::HostCall, id),
+            Inst::Flush => Op::new(OpCode::Flush),
+            Inst::Return => Op::new(OpCode::Return),
+            Inst::Halt => Op::new(OpCode::Halt),
+        });
+    }
+
+    Ok(Program {
+        code,
+        strings,
+        symbols,
+        offsets,
+        data: data.concat(),
+        data_base,
+        functions,
+        declared,
+        tables,
+        names,
+        lines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoded_targets_are_labels() {
+        let instructions = vec![
+            Inst::Jump(Jump::Unconditional, 7),
+            Inst::JumpTable(vec![9, 7]),
+            Inst::Call(9),
+            Inst::Label(9),
+            Inst::Halt,
+            Inst::Label(7),
+            Inst::Return,
+        ];
+        let program = load(instructions, Vec::new(), Vec::new(), HashMap::new(), Vec::new()).unwrap();
+        let decoded: Vec<String> = (0..3).map(|index| format!("{:?}", program.inst(index).unwrap())).collect();
+
+        assert_eq!(decoded, ["Jump(Unconditional, 7)", "JumpTable([9, 7])", "Call(9)"]);
+    }
+
+    #[test]
+    fn strings_are_nul_terminated() {
+        let instructions = vec![
+            Inst::MemOp(MemOp::InsertStr(String::from("ab"))),
+            Inst::MemOp(MemOp::InsertStr(String::from("ab\0"))),
+        ];
+        let program = load(instructions, Vec::new(), Vec::new(), HashMap::new(), Vec::new()).unwrap();
+
+        assert_eq!(program.strings, vec![b"ab\0".to_vec(), b"ab\0".to_vec()]);
+    }
+}
@@ -1,16 +1,22 @@
 mod disassemble;
-mod syscall;
-mod parser;
-mod exec;
-mod log;
 
-use parser::Parser;
-use exec::Machine;
-use lib_stacked::*;
+use lib_stacked::parser::Parser;
+use lib_stacked::exec::{self, Machine, Status};
+use lib_stacked::{CodeGen, Inst};
+use lib_stacked::verify;
+use lib_stacked::loader;
+use lib_stacked::linker::{self, Object};
+use lib_stacked::log;
+use lib_stacked::loader::Program;
+use lib_stacked::snapshot::{Snapshot, SnapshotError};
+use lib_stacked::syscall::{MemoryFs, SyscallPolicy};
 
-use clap::{Parser as ClapParser, Subcommand};
+use clap::{Args as ClapArgs, Parser as ClapParser, Subcommand};
 
+use std::fs::{self, File};
+use std::io::BufReader;
 use std::process;
+use std::sync::Arc;
 
 #[derive(ClapParser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -20,75 +26,319 @@ struct Args {
 
     #[arg(long, short, action)]
     debug: bool,
+
+    /// Maximum amount of values on the operand stack
+    #[arg(long, default_value_t = exec::DEFAULT_STACK_LIMIT)]
+    stack_limit: usize,
+
+    /// Maximum depth of nested calls
+    #[arg(long, default_value_t = exec::DEFAULT_CALL_LIMIT)]
+    call_limit: usize,
+}
+
+// What the guest is allowed to touch on the host, a doc comment would become the about of
+// every command it is flattened into.
+#[derive(ClapArgs, Debug)]
+struct Sandbox {
+    /// Allow opening files below this directory for reading, can be repeated
+    #[arg(long, value_name = "DIR")]
+    allow_read: Vec<String>,
+
+    /// Allow opening files below this directory for writing, can be repeated
+    #[arg(long, value_name = "DIR")]
+    allow_write: Vec<String>,
+
+    /// Deny every syscall that is not allowed explicitly
+    #[arg(long, action)]
+    deny_all: bool,
+
+    /// Open files in an in-memory copy of this directory instead of the real filesystem
+    #[arg(long, value_name = "DIR")]
+    vfs_dir: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    Exec { file: String },
+    Exec {
+        file: String,
+
+        #[command(flatten)]
+        sandbox: Sandbox,
+
+        /// Stop after this many instructions and save the state of the machine to the file
+        #[arg(long, num_args = 2, value_names = ["STEPS", "OUT"])]
+        snapshot_at: Option<Vec<String>>,
+    },
+    /// Continue a program from a snapshot taken with `exec --snapshot-at`
+    Resume {
+        snapshot: String,
+
+        #[command(flatten)]
+        sandbox: Sandbox,
+    },
     Disassemble { file: String },
+    Verify { file: String },
+    Optimize {
+        file: String,
+
+        #[arg(long, short)]
+        output: String,
+
+        /// Drop the symbol section and the line table from the output
+        #[arg(long, action)]
+        strip: bool,
+    },
+    /// Merge relocatable objects into one program, the first object is the entry point
+    Link {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        #[arg(long, short)]
+        output: String,
+
+        /// Drop the symbol section and the line table from the output
+        #[arg(long, action)]
+        strip: bool,
+    },
+}
+
+fn parse(file: &str) -> (Parser, Vec<Inst>) {
+    let mut parser = match Parser::new(file) {
+        Ok(parser) => parser,
+        Err(err) => {
+            log::error(&format!("failed to initialize parser: {}", err));
+            process::exit(1);
+        },
+    };
+
+    match parser.parse() {
+        Ok(instructions) => (parser, instructions),
+        Err(err) => {
+            log::error(&format!("failed to parse: {}", err));
+            process::exit(1);
+        },
+    }
+}
+
+fn load(parser: Parser, instructions: Vec<Inst>) -> Program {
+    match loader::load(instructions, parser.data, parser.functions, parser.symbols, parser.lines) {
+        Ok(program) => program,
+        Err(err) => {
+            log::error(&format!("failed to load: {}", err));
+            process::exit(1);
+        },
+    }
+}
+
+fn machine(args: &Args, sandbox: &Sandbox) -> Machine {
+    let mut policy = if sandbox.deny_all {
+        SyscallPolicy::deny_all()
+    } else {
+        SyscallPolicy::allow_all()
+    };
+
+    for dir in &sandbox.allow_read {
+        policy = policy.allow_read(dir);
+    }
+
+    for dir in &sandbox.allow_write {
+        policy = policy.allow_write(dir);
+    }
+
+    let mut vm = Machine::new(args.debug)
+        .stack_limit(args.stack_limit)
+        .call_limit(args.call_limit)
+        .syscall_policy(policy);
+
+    if let Some(dir) = &sandbox.vfs_dir {
+        match MemoryFs::load_dir(dir) {
+            Ok(fs) => vm = vm.backend(fs),
+            Err(err) => {
+                log::error(&format!("failed to load {dir}: {err}"));
+                process::exit(1);
+            },
+        }
+    }
+
+    vm
 }
 
 fn main() {
     let args = Args::parse();
 
     match &args.command {
-        Commands::Exec { file } | Commands::Disassemble { file } => {
-            let mut parser = match Parser::new(&file) {
-                Ok(parser) => parser,
+        Commands::Exec { file, sandbox, snapshot_at } => {
+            let (parser, instructions) = parse(file);
+            let program = load(parser, instructions);
+            let mut vm = machine(&args, sandbox);
+
+            let Some([steps, out]) = snapshot_at.as_deref() else {
+                if let Err(err) = vm.exec(&program) {
+                    log::error(&err.to_string());
+                    process::exit(1);
+                }
+
+                return;
+            };
+
+            let Ok(steps) = steps.parse::<u64>() else {
+                log::error(&format!("invalid amount of steps `{steps}`"));
+                process::exit(1);
+            };
+
+            vm.load(Arc::new(program));
+
+            match vm.run(steps) {
+                Ok(Status::Paused) => {},
+                Ok(_) => {
+                    log::error(&format!("program stopped before {steps} steps, no snapshot written"));
+                    process::exit(1);
+                },
                 Err(err) => {
-                    log::error(&format!("failed to initialize parser: {}", err.to_string()));
+                    log::error(&err.to_string());
+                    process::exit(1);
+                },
+            }
+
+            let mut snapshot = vm.snapshot();
+
+            snapshot.program = match fs::read(file) {
+                Ok(program) => Some(program),
+                Err(err) => {
+                    log::error(&format!("failed to read {file}: {err}"));
                     process::exit(1);
                 },
             };
 
-            if let Commands::Exec { .. } = args.command {
-                let instructions = match parser.parse() {
-                    Ok(instructions) => instructions,
-                    Err(err) => {
-                        log::error(&format!("failed to parse: {}", err.to_string()));
-                        process::exit(1);
-                    },
-                };
-
-                let mut vm = Machine::new(args.debug);
-
-                match vm.exec(instructions, parser.labels) {
-                    Err(exec::ErrorKind::StackUnderflow) => {
-                        log::error("stackunderflow");
-                        process::exit(1);
-                    },
-                    Err(exec::ErrorKind::OutOfBounds) => {
-                        log::error("out of bounds");
-                        process::exit(1);
-                    },
-                    Err(exec::ErrorKind::UnknownLabel(addr)) => {
-                        log::error(&format!("unknown label `{addr}`"));
-                        process::exit(1);
-                    },
-                    Err(exec::ErrorKind::Syscall(err)) => {
-                        log::error(&format!("{err}"));
-                        process::exit(1);
-                    },
-                    Err(exec::ErrorKind::UnknownSyscall) => {
-                        log::error("unknown syscall");
-                        process::exit(1);
-                    },
-                    Ok(_) => (),
+            if let Err(err) = File::create(out).and_then(|mut output| snapshot.write(&mut output)) {
+                log::error(&format!("failed to write {out}: {err}"));
+                process::exit(1);
+            }
+
+            log::info(&format!("saved the state after {steps} steps to {out}"));
+        },
+        Commands::Resume { snapshot, sandbox } => {
+            let snapshot = match File::open(snapshot).map_err(SnapshotError::from).and_then(|mut input| Snapshot::read(&mut BufReader::new(&mut input))) {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    log::error(&format!("failed to read {snapshot}: {err}"));
+                    process::exit(1);
+                },
+            };
+
+            let Some(bytes) = snapshot.program.clone() else {
+                log::error("the snapshot does not contain its program");
+                process::exit(1);
+            };
+
+            let program = match Program::from_bytes(bytes) {
+                Ok(program) => program,
+                Err(err) => {
+                    log::error(&format!("failed to load the program of the snapshot: {}", err));
+                    process::exit(1);
+                },
+            };
+
+            let mut vm = machine(&args, sandbox);
+
+            vm.load(Arc::new(program));
+
+            if let Err(err) = vm.restore(&snapshot) {
+                log::error(&format!("failed to restore: {err}"));
+                process::exit(1);
+            }
+
+            if let Err(err) = vm.resume() {
+                log::error(&err.to_string());
+                process::exit(1);
+            }
+        },
+        Commands::Disassemble { file } => {
+            let (parser, instructions) = parse(file);
+
+            disassemble::disassemble(instructions, parser.data, parser.functions, parser.symbols, parser.lines);
+        },
+        Commands::Verify { file } => {
+            let (parser, instructions) = parse(file);
+
+            if let Err(errors) = verify::verify(&instructions, &parser.functions) {
+                for err in &errors {
+                    log::error(&err.to_string());
                 }
-            } else {
-                let instructions = match parser.parse() {
-                    Ok(instructions) => instructions,
-                    Err(err) => {
-                        log::error(&format!("failed to parse: {}", err.to_string()));
-                        process::exit(1);
-                    },
-                };
-
-                disassemble::disassemble(instructions);
+
+                process::exit(1);
             }
 
+            log::info(&format!("verified {} instructions", instructions.len()));
         },
-    }
+        Commands::Optimize { file, output, strip } => {
+            let (parser, instructions) = parse(file);
+            let before = instructions.len();
 
-}
+            let mut codegen = match CodeGen::new(output) {
+                Ok(codegen) => codegen,
+                Err(err) => {
+                    log::error(&format!("failed to initialize codegen: {}", err));
+                    process::exit(1);
+                },
+            };
 
+            Object::new(file, parser, instructions).emit(&mut codegen);
+
+            if *strip {
+                codegen.strip();
+            }
+
+            codegen.optimize();
+
+            log::info(&format!("optimized {} instructions to {}", before, codegen.len()));
+
+            if let Err(err) = codegen.output() {
+                log::error(&format!("failed to output: {}", err));
+                process::exit(1);
+            }
+        },
+        Commands::Link { files, output, strip } => {
+            let objects: Vec<Object> = files
+                .iter()
+                .map(|file| {
+                    let (parser, instructions) = parse(file);
+
+                    Object::new(file, parser, instructions)
+                })
+                .collect();
+
+            let linked = match linker::link(objects) {
+                Ok(linked) => linked,
+                Err(errors) => {
+                    for err in &errors {
+                        log::error(&err.to_string());
+                    }
+
+                    process::exit(1);
+                },
+            };
+
+            let mut codegen = match CodeGen::new(output) {
+                Ok(codegen) => codegen,
+                Err(err) => {
+                    log::error(&format!("failed to initialize codegen: {}", err));
+                    process::exit(1);
+                },
+            };
+
+            log::info(&format!("linked {} objects into {} instructions", files.len(), linked.instructions.len()));
+
+            linked.emit(&mut codegen);
+
+            if *strip {
+                codegen.strip();
+            }
+
+            if let Err(err) = codegen.output() {
+                log::error(&format!("failed to output: {}", err));
+                process::exit(1);
+            }
+        },
+    }
+}
@@ -0,0 +1,448 @@
+use crate::{Inst, ExprKind, Jump, StackOp, MemOp, LocalOp};
+
+use std::collections::{HashMap, HashSet};
+
+/// An instruction and the index of the span it was generated from, passes keep the span of
+/// every instruction they keep.
+pub type Spanned = (Inst, Option<usize>);
+
+/// A pass rewrites the program and reports whether it changed anything, labels in the roots
+/// have to keep their meaning.
+type Pass = fn(Vec<Spanned>, &[u32]) -> (Vec<Spanned>, bool);
+
+fn fold(lhs: u32, rhs: u32, kind: &ExprKind) -> Option<u32> {
+    match kind {
+        ExprKind::Add => lhs.checked_add(rhs),
+        ExprKind::Sub => lhs.checked_sub(rhs),
+        ExprKind::Mul => lhs.checked_mul(rhs),
+        ExprKind::Div => lhs.checked_div(rhs),
+    }
+}
+
+fn compare(lhs: u32, rhs: u32) -> u32 {
+    if lhs == rhs {
+        0
+    } else if lhs > rhs {
+        1
+    } else {
+        2
+    }
+}
+
+fn taken(jump: &Jump, result: u32) -> bool {
+    match jump {
+        Jump::Unconditional => true,
+        Jump::Equal => result == 0,
+        Jump::NotEqual => result != 0,
+        Jump::Greater => result == 1,
+        Jump::Lesser => result == 2,
+    }
+}
+
+/// Whether the instruction leaves at least one value on the stack whenever it succeeds.
+fn leaves_value(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::StackOp(StackOp::Push(_) | StackOp::PushData(_) | StackOp::PushFunc(_) | StackOp::Dup | StackOp::Swap | StackOp::Rot | StackOp::Cmp)
+        | Inst::StackOp(StackOp::Over | StackOp::Nip | StackOp::Tuck | StackOp::Pick(_) | StackOp::Roll(_) | StackOp::Depth)
+        | Inst::BinaryExpr(_)
+        | Inst::MemOp(MemOp::Load | MemOp::InsertBytes(_))
+        | Inst::LocalOp(LocalOp::Load(_))
+    )
+}
+
+/// Whether the instruction adds a value to the stack whenever it succeeds, so the stack holds
+/// one more value afterwards.
+fn adds_value(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::StackOp(StackOp::Push(_) | StackOp::PushData(_) | StackOp::PushFunc(_) | StackOp::Dup)
+        | Inst::StackOp(StackOp::Over | StackOp::Tuck | StackOp::Pick(_) | StackOp::Depth)
+        | Inst::LocalOp(LocalOp::Load(_))
+    )
+}
+
+/// Rewrite the end of `out`, returns true if anything changed.
+///
+/// A folded instruction keeps the span of the first instruction it replaces.
+fn reduce(out: &mut Vec<Spanned>) -> bool {
+    let len = out.len();
+
+    match out.as_slice() {
+        [.., (Inst::StackOp(StackOp::Push(lhs)), span), (Inst::StackOp(StackOp::Push(rhs)), _), (Inst::BinaryExpr(kind), _)] => {
+            if let Some(result) = fold(*lhs, *rhs, kind) {
+                let span = *span;
+
+                out.truncate(len - 3);
+                out.push((Inst::StackOp(StackOp::Push(result)), span));
+
+                return true;
+            }
+        },
+        [.., (Inst::StackOp(StackOp::Push(lhs)), span), (Inst::StackOp(StackOp::Push(rhs)), _), (Inst::StackOp(StackOp::Cmp), _)] => {
+            let result = compare(*lhs, *rhs);
+            let span = *span;
+
+            out.truncate(len - 3);
+            out.push((Inst::StackOp(StackOp::Push(result)), span));
+
+            return true;
+        },
+        [.., (Inst::StackOp(StackOp::Push(result)), span), (Inst::Jump(jump, label), _)] if *jump != Jump::Unconditional => {
+            let jump = taken(jump, *result).then_some((Inst::Jump(Jump::Unconditional, *label), *span));
+
+            out.truncate(len - 2);
+            out.extend(jump);
+
+            return true;
+        },
+        // `Dup` on an empty stack underflows, so it is only removed after a value was pushed.
+        [.., (previous, _), (Inst::StackOp(StackOp::Dup), _), (Inst::StackOp(StackOp::Pop), _)] if leaves_value(previous) => {
+            out.truncate(len - 2);
+
+            return true;
+        },
+        // `Swap` needs two values, so the pair is only removed after two values were pushed.
+        [.., (first, _), (second, _), (Inst::StackOp(StackOp::Swap), _), (Inst::StackOp(StackOp::Swap), _)] if leaves_value(first) && adds_value(second) => {
+            out.truncate(len - 2);
+
+            return true;
+        },
+        [.., (Inst::StackOp(StackOp::Push(_)), _), (Inst::StackOp(StackOp::Pop), _)] => {
+            out.truncate(len - 2);
+
+            return true;
+        },
+        _ => {},
+    }
+
+    false
+}
+
+/// Constant folding and removal of instruction pairs that cancel out.
+fn peephole(instructions: Vec<Spanned>, _roots: &[u32]) -> (Vec<Spanned>, bool) {
+    let mut out: Vec<Spanned> = Vec::with_capacity(instructions.len());
+    let mut changed = false;
+
+    for inst in instructions {
+        out.push(inst);
+
+        while reduce(&mut out) {
+            changed = true;
+        }
+    }
+
+    (out, changed)
+}
+
+/// Remove code following a `Halt`, `Return` or unconditional jump up until the next label.
+fn dead_code(instructions: Vec<Spanned>, _roots: &[u32]) -> (Vec<Spanned>, bool) {
+    let len = instructions.len();
+    let mut reachable = true;
+
+    let out: Vec<Spanned> = instructions
+        .into_iter()
+        .filter(|(inst, _)| {
+            if let Inst::Label(_) = inst {
+                reachable = true;
+            }
+
+            let keep = reachable;
+
+            if let Inst::Halt | Inst::Return | Inst::Jump(Jump::Unconditional, _) = inst {
+                reachable = false;
+            }
+
+            keep
+        })
+        .collect();
+
+    let changed = out.len() != len;
+
+    (out, changed)
+}
+
+/// Point jumps and calls to labels that only jump elsewhere at the final destination, and remove
+/// unconditional jumps to the labels directly following them.
+///
+/// Calls are never threaded to or through a root, a call to a label of the function table is
+/// checked against its signature and has to keep calling that label.
+fn jumps(mut instructions: Vec<Spanned>, roots: &[u32]) -> (Vec<Spanned>, bool) {
+    let mut forwards: HashMap<u32, u32> = HashMap::new();
+
+    for (index, (inst, _)) in instructions.iter().enumerate() {
+        if let Inst::Label(label) = inst {
+            let next = instructions[index..]
+                .iter()
+                .map(|(inst, _)| inst)
+                .find(|inst| !matches!(inst, Inst::Label(_)));
+
+            if let Some(Inst::Jump(Jump::Unconditional, target)) = next {
+                forwards.insert(*label, *target);
+            }
+        }
+    }
+
+    let destination = |label: u32, call: bool| {
+        let mut visited: HashSet<u32> = HashSet::new();
+        let mut current = label;
+
+        if call && roots.contains(&label) {
+            return label;
+        }
+
+        while let Some(next) = forwards.get(&current) {
+            if !visited.insert(current) {
+                return label;
+            }
+
+            if call && roots.contains(next) {
+                break;
+            }
+
+            current = *next;
+        }
+
+        current
+    };
+
+    let mut changed = false;
+
+    for (inst, _) in instructions.iter_mut() {
+        let call = matches!(inst, Inst::Call(_));
+
+        if let Inst::Jump(_, label) | Inst::Call(label) = inst {
+            let target = destination(*label, call);
+
+            if target != *label {
+                *label = target;
+                changed = true;
+            }
+        }
+    }
+
+    let mut index = 0;
+
+    while index < instructions.len() {
+        if let (Inst::Jump(Jump::Unconditional, target), _) = instructions[index] {
+            let falls_through = instructions[index + 1..]
+                .iter()
+                .take_while(|(inst, _)| matches!(inst, Inst::Label(_)))
+                .any(|(inst, _)| matches!(inst, Inst::Label(label) if *label == target));
+
+            if falls_through {
+                instructions.remove(index);
+                changed = true;
+                continue;
+            }
+        }
+
+        index += 1;
+    }
+
+    (instructions, changed)
+}
+
+/// Remove labels that are never jumped to, called or referenced and are not a root.
+fn unused_labels(instructions: Vec<Spanned>, roots: &[u32]) -> (Vec<Spanned>, bool) {
+    let used: HashSet<u32> = instructions
+        .iter()
+        .flat_map(|(inst, _)| inst.targets())
+        .chain(roots)
+        .copied()
+        .collect();
+
+    let len = instructions.len();
+
+    let out: Vec<Spanned> = instructions
+        .into_iter()
+        .filter(|(inst, _)| !matches!(inst, Inst::Label(label) if !used.contains(label)))
+        .collect();
+
+    let changed = out.len() != len;
+
+    (out, changed)
+}
+
+/// Run the peephole passes over a program until none of them changes it anymore.
+///
+/// Performs constant folding of arithmetic, comparisons and conditional jumps on constants,
+/// removes `Push; Pop`, and `Dup; Pop` and `Swap; Swap` where they can not underflow,
+/// eliminates dead code, threads jumps through labels that only jump elsewhere and removes
+/// labels that are never used. Labels in `roots`, such as those of the function table, are
+/// always kept.
+pub fn optimize(instructions: Vec<Inst>, roots: &[u32]) -> Vec<Inst> {
+    optimize_spanned(instructions.into_iter().map(|inst| (inst, None)).collect(), roots)
+        .into_iter()
+        .map(|(inst, _)| inst)
+        .collect()
+}
+
+/// [`optimize`] a program whose instructions carry the index of the span they were generated
+/// from.
+pub fn optimize_spanned(mut instructions: Vec<Spanned>, roots: &[u32]) -> Vec<Spanned> {
+    let passes: [Pass; 4] = [peephole, dead_code, jumps, unused_labels];
+
+    loop {
+        let mut changed = false;
+
+        for pass in passes {
+            let (out, pass_changed) = pass(instructions, roots);
+
+            instructions = out;
+            changed |= pass_changed;
+        }
+
+        if !changed {
+            return instructions;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Function, exec::Machine, loader::{self, OpCode}};
+
+    use std::collections::HashMap;
+
+    /// The outcome of running a program and what it printed.
+    fn run(instructions: Vec<Inst>) -> (Result<(), String>, String) {
+        let program = loader::load(instructions, Vec::new(), Vec::new(), HashMap::new(), Vec::new()).unwrap();
+        let output = Machine::new(false).run_capture(&program, b"");
+
+        (output.result.map_err(|err| err.kind.to_string()), String::from_utf8(output.stdout).unwrap())
+    }
+
+    /// Check that `pass` changes the program without changing what it does.
+    fn assert_preserved(pass: Pass, instructions: Vec<Inst>) {
+        let before = run(instructions.clone());
+        let (optimized, changed) = pass(instructions.into_iter().map(|inst| (inst, None)).collect(), &[]);
+
+        assert!(changed);
+        assert_eq!(before, run(optimized.into_iter().map(|(inst, _)| inst).collect()));
+    }
+
+    fn push(value: u32) -> Inst {
+        Inst::StackOp(StackOp::Push(value))
+    }
+
+    fn dump() -> Inst {
+        Inst::StackOp(StackOp::Dump)
+    }
+
+    #[test]
+    fn folding_preserves_semantics() {
+        assert_preserved(peephole, vec![
+            push(6), push(3), Inst::BinaryExpr(ExprKind::Sub),
+            push(2), Inst::BinaryExpr(ExprKind::Mul), dump(),
+            push(3), push(3), Inst::StackOp(StackOp::Cmp), Inst::Jump(Jump::Equal, 0),
+            push(1), dump(),
+            Inst::Label(0),
+            push(7), Inst::StackOp(StackOp::Dup), Inst::StackOp(StackOp::Pop), dump(),
+            push(8), push(9), Inst::StackOp(StackOp::Swap), Inst::StackOp(StackOp::Swap), dump(), dump(),
+        ]);
+    }
+
+    #[test]
+    fn dead_code_preserves_semantics() {
+        assert_preserved(dead_code, vec![
+            push(1), dump(), Inst::Jump(Jump::Unconditional, 0),
+            push(2), dump(),
+            Inst::Label(0),
+            push(3), dump(), Inst::Halt,
+            push(4), dump(),
+        ]);
+    }
+
+    #[test]
+    fn threading_preserves_semantics() {
+        assert_preserved(jumps, vec![
+            Inst::Call(2),
+            Inst::Jump(Jump::Unconditional, 0),
+            Inst::Label(1),
+            push(5), dump(), Inst::Halt,
+            Inst::Label(0),
+            Inst::Jump(Jump::Unconditional, 1),
+
+            Inst::Label(2),
+            Inst::Jump(Jump::Unconditional, 3),
+            Inst::Label(3),
+            push(4), dump(), Inst::Return,
+        ]);
+    }
+
+    #[test]
+    fn unused_labels_preserve_semantics() {
+        assert_preserved(unused_labels, vec![
+            Inst::Label(5),
+            push(1), dump(),
+            Inst::Label(6),
+            push(2), Inst::Jump(Jump::Unconditional, 7),
+            Inst::Label(7),
+            dump(),
+        ]);
+    }
+
+    #[test]
+    fn dup_pop_keeps_stack_underflow() {
+        let instructions = vec![Inst::StackOp(StackOp::Dup), Inst::StackOp(StackOp::Pop), push(1), dump()];
+
+        assert_eq!(optimize(instructions.clone(), &[]).len(), instructions.len());
+        assert_eq!(run(optimize(instructions.clone(), &[])), run(instructions));
+    }
+
+    #[test]
+    fn swap_swap_keeps_stack_underflow() {
+        let swap = || [Inst::StackOp(StackOp::Swap), Inst::StackOp(StackOp::Swap)];
+        let programs = [
+            [swap().as_slice(), &[push(1), dump()]].concat(),
+            [&[push(1)], swap().as_slice(), &[dump()]].concat(),
+            [&[push(1), push(2), Inst::BinaryExpr(ExprKind::Add)], swap().as_slice(), &[dump()]].concat(),
+        ];
+
+        for instructions in programs {
+            let optimized = optimize(instructions.clone(), &[]);
+
+            assert_eq!(optimized.iter().filter(|inst| matches!(inst, Inst::StackOp(StackOp::Swap))).count(), 2);
+            assert_eq!(run(optimized), run(instructions));
+        }
+    }
+
+    #[test]
+    fn spans_are_kept() {
+        let instructions = vec![
+            push(2), push(3), Inst::BinaryExpr(ExprKind::Add),
+            dump(), Inst::Jump(Jump::Unconditional, 0),
+            push(4), dump(),
+            Inst::Label(0),
+            Inst::Halt,
+        ];
+        let spanned = instructions.into_iter().enumerate().map(|(index, inst)| (inst, Some(index))).collect();
+        let spans: Vec<Option<usize>> = optimize_spanned(spanned, &[]).into_iter().map(|(_, span)| span).collect();
+
+        assert_eq!(spans, vec![Some(0), Some(3), Some(8)]);
+    }
+
+    #[test]
+    fn calls_to_declared_functions_are_not_threaded() {
+        let instructions = vec![
+            Inst::StackOp(StackOp::Push(1)),
+            Inst::Call(0),
+            Inst::StackOp(StackOp::Dump),
+            Inst::Halt,
+
+            Inst::Label(0),
+            Inst::Jump(Jump::Unconditional, 1),
+            Inst::Label(1),
+            Inst::Return,
+        ];
+
+        let functions = vec![Function { label: 0, name: String::from("f"), params: 1, results: 1, locals: 0 }];
+        let optimized = optimize(instructions, &[0]);
+        let program = loader::load(optimized, Vec::new(), functions, HashMap::new(), Vec::new()).unwrap();
+
+        assert!(program.code.iter().any(|op| op.code == OpCode::CallFunction));
+    }
+}
@@ -1,25 +1,49 @@
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Cursor, Read};
 use std::collections::HashMap;
 use std::fs::File;
 use std::mem;
 
-use crate::{ExprKind, Jump, StackOp, Inst, MemOp};
+use crate::{ExprKind, Jump, StackOp, Inst, MemOp, LocalOp, Function, Span, Section, MAGIC, VERSION};
 
 
 pub struct Parser {
-    reader: BufReader<File>,
-    pub labels: HashMap<u32, u32>,
+    reader: Box<dyn Read>,
+    /// The items of the data section, indexed by the id used in `PushData`.
+    pub data: Vec<Vec<u8>>,
+    pub functions: Vec<Function>,
+    /// Label to the name given to it in the symbol section.
+    pub symbols: HashMap<u32, String>,
+    /// The instruction index every span of the line table starts at.
+    pub lines: Vec<(usize, Span)>,
+    /// Labels this object makes available to others by name.
+    pub exports: Vec<(u32, String)>,
+    /// Labels this object uses but expects another object to define.
+    pub imports: Vec<(u32, String)>,
 }
 
 impl Parser {
     pub fn new(file: &str) -> Result<Parser, Box<dyn std::error::Error>> {
-        Ok(Parser {
-            reader: BufReader::new(File::open(file)?),
-            labels: HashMap::new(),
-        })
+        Ok(Parser::with_reader(Box::new(BufReader::new(File::open(file)?))))
     }
 
-    fn from_bytes(&self, bytes: [u8; 4]) -> u32 {
+    /// A parser for a program that is already in memory.
+    pub fn from_bytes(bytes: Vec<u8>) -> Parser {
+        Parser::with_reader(Box::new(Cursor::new(bytes)))
+    }
+
+    fn with_reader(reader: Box<dyn Read>) -> Parser {
+        Parser {
+            reader,
+            data: Vec::new(),
+            functions: Vec::new(),
+            symbols: HashMap::new(),
+            lines: Vec::new(),
+            exports: Vec::new(),
+            imports: Vec::new(),
+        }
+    }
+
+    fn to_int(&self, bytes: [u8; 4]) -> u32 {
         if cfg!(target_endian = "big") {
             u32::from_be_bytes(bytes)
         } else {
@@ -27,65 +51,214 @@ impl Parser {
         }
     }
 
+    fn read_int(&self, reader: &mut impl Read) -> Result<u32, Box<dyn std::error::Error>> {
+        let mut value = [0u8; mem::size_of::<u32>()];
+
+        reader.read_exact(&mut value)?;
+
+        Ok(self.to_int(value))
+    }
+
+    fn read_names(&self, reader: &mut impl Read) -> Result<Vec<(u32, String)>, Box<dyn std::error::Error>> {
+        let mut names: Vec<(u32, String)> = Vec::new();
+
+        for _ in 0..self.read_int(reader)? {
+            let label = self.read_int(reader)?;
+            let name = String::from_utf8(self.read_bytes(reader)?)?;
+
+            names.push((label, name));
+        }
+
+        Ok(names)
+    }
+
+    fn read_bytes(&self, reader: &mut impl Read) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let len = self.read_int(reader)? as usize;
+        let mut bytes: Vec<u8> = Vec::new();
+
+        reader.take(len as u64).read_to_end(&mut bytes)?;
+
+        if bytes.len() != len {
+            return Err("unexpected end of file".into());
+        }
+
+        Ok(bytes)
+    }
+
+    /// Parse a file, files that don't start with the magic bytes only contain code.
     pub fn parse(&mut self) -> Result<Vec<Inst>, Box<dyn std::error::Error>> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        self.reader.read_to_end(&mut bytes)?;
+
+        if !bytes.starts_with(&MAGIC) {
+            return self.parse_code(&mut Cursor::new(bytes));
+        }
+
+        let mut reader = Cursor::new(&bytes[MAGIC.len()..]);
+        let mut version = [0u8; mem::size_of::<u8>()];
+
+        reader.read_exact(&mut version)?;
+
+        if version[0] > VERSION {
+            return Err(format!("unsupported version {}", version[0]).into());
+        }
+
+        let mut instructions: Vec<Inst> = Vec::new();
+        let mut kind = [0u8; mem::size_of::<u8>()];
+
+        while reader.read_exact(&mut kind).is_ok() {
+            let mut section = Cursor::new(self.read_bytes(&mut reader)?);
+
+            match Section::from_id(kind[0]) {
+                Some(Section::Code) => {
+                    instructions = self.parse_code(&mut section)?;
+                },
+                Some(Section::Data) => {
+                    for _ in 0..self.read_int(&mut section)? {
+                        let item = self.read_bytes(&mut section)?;
+
+                        self.data.push(item);
+                    }
+                },
+                Some(Section::Functions) => {
+                    for _ in 0..self.read_int(&mut section)? {
+                        let label = self.read_int(&mut section)?;
+                        let name = String::from_utf8(self.read_bytes(&mut section)?)?;
+
+                        self.functions.push(Function {
+                            label,
+                            name,
+                            params: self.read_int(&mut section)?,
+                            results: self.read_int(&mut section)?,
+                            locals: self.read_int(&mut section)?,
+                        });
+                    }
+                },
+                Some(Section::Symbols) => {
+                    self.symbols.extend(self.read_names(&mut section)?);
+                },
+                Some(Section::Exports) => {
+                    self.exports = self.read_names(&mut section)?;
+                },
+                Some(Section::Imports) => {
+                    self.imports = self.read_names(&mut section)?;
+                },
+                Some(Section::Lines) => {
+                    let mut files: Vec<String> = Vec::new();
+
+                    for _ in 0..self.read_int(&mut section)? {
+                        files.push(String::from_utf8(self.read_bytes(&mut section)?)?);
+                    }
+
+                    for _ in 0..self.read_int(&mut section)? {
+                        let index = self.read_int(&mut section)? as usize;
+                        let file = files
+                            .get(self.read_int(&mut section)? as usize)
+                            .ok_or("line table refers to an unknown file")?
+                            .clone();
+
+                        self.lines.push((index, Span {
+                            file,
+                            line: self.read_int(&mut section)?,
+                            column: self.read_int(&mut section)?,
+                        }));
+                    }
+
+                    self.lines.sort_by_key(|(index, _)| *index);
+                },
+                None => {},
+            }
+        }
+
+        Ok(instructions)
+    }
+
+    fn parse_code(&mut self, reader: &mut impl Read) -> Result<Vec<Inst>, Box<dyn std::error::Error>> {
         let mut instructions: Vec<Inst> = Vec::new();
 
         loop {
             let mut buffer = [0u8; mem::size_of::<u8>()];
 
-            match self.reader.read_exact(&mut buffer) {
-                Err(_) => break,
-                _ => {},
+            if reader.read_exact(&mut buffer).is_err() {
+                break;
             }
 
             match buffer[0] {
-                0x4C | 0x01 | 0x6A | 0x6B | 0x6C | 0x6D | 0x6E | 0x2F => {
+                0x4C | 0x01 | 0x08 | 0x10 | 0x0C | 0x0E | 0x30 | 0x32 | 0x33 | 0x6A | 0x6B | 0x6C | 0x6D | 0x6E | 0x2F | 0x54 => {
                     let mut value = [0u8; mem::size_of::<u32>()];
 
-                    match self.reader.read_exact(&mut value) {
-                        Err(_) => break,
-                        _ => {},
+                    if reader.read_exact(&mut value).is_err() {
+                        break;
                     }
 
                     match buffer[0] {
                         0x4C => {
-                            self.labels.insert(self.from_bytes(value), instructions.len() as u32);
-                            instructions.push(Inst::Label(self.from_bytes(value)));
+                            instructions.push(Inst::Label(self.to_int(value)));
                         },
 
-                        0x01 => { instructions.push(Inst::StackOp(StackOp::Push(self.from_bytes(value)))); },
+                        0x01 => { instructions.push(Inst::StackOp(StackOp::Push(self.to_int(value)))); },
+                        0x08 => { instructions.push(Inst::StackOp(StackOp::PushData(self.to_int(value)))); },
+                        0x10 => { instructions.push(Inst::StackOp(StackOp::PushFunc(self.to_int(value)))); },
+                        0x0C => { instructions.push(Inst::StackOp(StackOp::Pick(self.to_int(value)))); },
+                        0x0E => { instructions.push(Inst::StackOp(StackOp::Roll(self.to_int(value)))); },
 
-                        0x2F => { instructions.push(Inst::Call(self.from_bytes(value))); },
+                        0x2F => { instructions.push(Inst::Call(self.to_int(value))); },
+                        0x54 => { instructions.push(Inst::HostCall(self.to_int(value))); },
 
-                        0x6A => { instructions.push(Inst::Jump(Jump::Unconditional, self.from_bytes(value))); },
-                        0x6B => { instructions.push(Inst::Jump(Jump::Equal, self.from_bytes(value))); },
-                        0x6E => { instructions.push(Inst::Jump(Jump::NotEqual, self.from_bytes(value))); },
-                        0x6C => { instructions.push(Inst::Jump(Jump::Greater, self.from_bytes(value))); },
-                        0x6D => { instructions.push(Inst::Jump(Jump::Lesser, self.from_bytes(value))); },
+                        0x30 => { instructions.push(Inst::LocalOp(LocalOp::Enter(self.to_int(value)))); },
+                        0x32 => { instructions.push(Inst::LocalOp(LocalOp::Load(self.to_int(value)))); },
+                        0x33 => { instructions.push(Inst::LocalOp(LocalOp::Store(self.to_int(value)))); },
+
+                        0x6A => { instructions.push(Inst::Jump(Jump::Unconditional, self.to_int(value))); },
+                        0x6B => { instructions.push(Inst::Jump(Jump::Equal, self.to_int(value))); },
+                        0x6E => { instructions.push(Inst::Jump(Jump::NotEqual, self.to_int(value))); },
+                        0x6C => { instructions.push(Inst::Jump(Jump::Greater, self.to_int(value))); },
+                        0x6D => { instructions.push(Inst::Jump(Jump::Lesser, self.to_int(value))); },
 
                         _ => {},
                     }
                 },
                 0x8C => {
-                    let mut string = String::new();
+                    let mut bytes: Vec<u8> = Vec::new();
 
                     loop {
                         let mut character = [0u8; mem::size_of::<u8>()];
 
-                        match self.reader.read_exact(&mut character) {
-                            Err(_) => break,
-                            _ => {},
+                        if reader.read_exact(&mut character).is_err() {
+                            break;
                         }
 
-                        string.push(character[0] as char);
+                        bytes.push(character[0]);
 
-                        if character[0] as char == '\0' {
+                        if character[0] == 0 {
                             break;
                         }
                     }
 
+                    let string = String::from_utf8(bytes)
+                        .map_err(|err| format!("string literal at instruction {} is not valid utf-8: {}", instructions.len(), err.utf8_error()))?;
+
                     instructions.push(Inst::MemOp(MemOp::InsertStr(string)));
                 },
+                0x8D => {
+                    let bytes = self.read_bytes(reader)?;
+
+                    instructions.push(Inst::MemOp(MemOp::InsertBytes(bytes)));
+                },
+                0x6F => {
+                    let count = self.read_int(reader)?;
+                    let mut labels: Vec<u32> = Vec::new();
+
+                    for _ in 0..count {
+                        labels.push(self.read_int(reader)?);
+                    }
+
+                    instructions.push(Inst::JumpTable(labels));
+                },
+                0x2E => { instructions.push(Inst::CallIndirect); },
+                0x31 => { instructions.push(Inst::LocalOp(LocalOp::Leave)); },
+
                 0x8A => { instructions.push(Inst::MemOp(MemOp::Load)); },
                 0x8B => { instructions.push(Inst::MemOp(MemOp::Store)); },
 
@@ -95,6 +268,10 @@ impl Parser {
                 0x07 => { instructions.push(Inst::StackOp(StackOp::Rot)); },
                 0x03 => { instructions.push(Inst::StackOp(StackOp::Dump)); },
                 0x43 => { instructions.push(Inst::StackOp(StackOp::Cmp)); },
+                0x09 => { instructions.push(Inst::StackOp(StackOp::Over)); },
+                0x0A => { instructions.push(Inst::StackOp(StackOp::Nip)); },
+                0x0B => { instructions.push(Inst::StackOp(StackOp::Tuck)); },
+                0x0F => { instructions.push(Inst::StackOp(StackOp::Depth)); },
 
                 0x28 => { instructions.push(Inst::BinaryExpr(ExprKind::Add)); },
                 0x29 => { instructions.push(Inst::BinaryExpr(ExprKind::Sub)); },
@@ -102,6 +279,7 @@ impl Parser {
                 0x2B => { instructions.push(Inst::BinaryExpr(ExprKind::Div)); },
 
                 0x53 => { instructions.push(Inst::Syscall); },
+                0x55 => { instructions.push(Inst::Flush); },
                 0x0D => { instructions.push(Inst::Return); },
                 0x04 => { instructions.push(Inst::Halt); },
                 _ => {},
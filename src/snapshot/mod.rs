@@ -0,0 +1,316 @@
+use crate::exec::Frame;
+
+use std::io::{self, Cursor, Read, Write};
+use std::fmt;
+use std::mem;
+
+/// Magic bytes at the start of a snapshot file.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"SNAP";
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+const STATE: u8 = 0x01;
+const STACK: u8 = 0x02;
+const LOCALS: u8 = 0x03;
+const FRAMES: u8 = 0x04;
+const MEMORY: u8 = 0x05;
+const PROGRAM: u8 = 0x06;
+
+/// Stored in place of the function index of a frame that did not call a declared function.
+const NO_FUNCTION: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The file does not start with [`SNAPSHOT_MAGIC`].
+    Magic,
+    UnsupportedVersion(u8),
+    /// A section ended before all of its values were read.
+    Truncated { section: u8 },
+    /// The memory does not have the size of the memory of a machine.
+    MemorySize { expected: usize, found: usize },
+    /// A call frame refers to instructions, functions, locals or stack values that do not exist.
+    InvalidFrame { index: usize },
+    /// The next instruction is not part of the program.
+    InvalidIp { ip: u32 },
+    /// A snapshot can only be restored into a machine that has its program loaded.
+    NoProgram,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{err}"),
+            SnapshotError::Magic => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {version}"),
+            SnapshotError::Truncated { section } => write!(f, "section {section:#04x} is truncated"),
+            SnapshotError::MemorySize { expected, found } => write!(f, "expected {expected} memory cells, found {found}"),
+            SnapshotError::InvalidFrame { index } => write!(f, "call frame {index} does not fit the program"),
+            SnapshotError::InvalidIp { ip } => write!(f, "instruction {ip} is out of bounds"),
+            SnapshotError::NoProgram => write!(f, "no program is loaded"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> SnapshotError {
+        SnapshotError::Io(err)
+    }
+}
+
+/// The state of a machine between two instructions, taken with `Machine::snapshot` and put back
+/// with `Machine::restore`.
+///
+/// Open files, host functions and the configuration of the machine are not part of it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// Index of the next instruction.
+    pub ip: u32,
+    pub stack: Vec<u32>,
+    pub locals: Vec<u32>,
+    pub(crate) frames: Vec<Frame>,
+    pub memory: Vec<u32>,
+    /// The bytecode the state belongs to, resuming it with another program is meaningless.
+    pub program: Option<Vec<u8>>,
+}
+
+fn write_section(writer: &mut impl Write, id: u8, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&[id])?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn ints(values: impl ExactSizeIterator<Item = u32>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity((values.len() + 1) * mem::size_of::<u32>());
+
+    bytes.extend((values.len() as u32).to_le_bytes());
+
+    for value in values {
+        bytes.extend(value.to_le_bytes());
+    }
+
+    bytes
+}
+
+/// Reads the little endian integers of one section.
+struct SectionReader {
+    id: u8,
+    reader: Cursor<Vec<u8>>,
+}
+
+impl SectionReader {
+    fn int(&mut self) -> Result<u32, SnapshotError> {
+        let mut value = [0u8; mem::size_of::<u32>()];
+
+        self.reader
+            .read_exact(&mut value)
+            .map_err(|_| SnapshotError::Truncated { section: self.id })?;
+
+        Ok(u32::from_le_bytes(value))
+    }
+
+    fn ints(&mut self) -> Result<Vec<u32>, SnapshotError> {
+        let count = self.int()?;
+
+        (0..count).map(|_| self.int()).collect()
+    }
+}
+
+impl Snapshot {
+    /// Write the snapshot, integers are little endian regardless of the host.
+    ///
+    /// The layout is [`SNAPSHOT_MAGIC`], [`SNAPSHOT_VERSION`] and sections prefixed by a one byte
+    /// id and a u32 length, like bytecode files.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&[SNAPSHOT_VERSION])?;
+
+        write_section(writer, STATE, &self.ip.to_le_bytes())?;
+        write_section(writer, STACK, &ints(self.stack.iter().copied()))?;
+        write_section(writer, LOCALS, &ints(self.locals.iter().copied()))?;
+
+        let mut frames: Vec<u8> = (self.frames.len() as u32).to_le_bytes().to_vec();
+
+        for frame in &self.frames {
+            for value in [frame.target, frame.ret, frame.base as u32, frame.function.unwrap_or(NO_FUNCTION), frame.floor as u32] {
+                frames.extend(value.to_le_bytes());
+            }
+        }
+
+        write_section(writer, FRAMES, &frames)?;
+        write_section(writer, MEMORY, &ints(self.memory.iter().copied()))?;
+
+        if let Some(program) = &self.program {
+            write_section(writer, PROGRAM, program)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a snapshot written by [`Snapshot::write`], unknown sections are skipped.
+    pub fn read(reader: &mut impl Read) -> Result<Snapshot, SnapshotError> {
+        let mut magic = [0u8; SNAPSHOT_MAGIC.len()];
+        let mut version = [0u8; mem::size_of::<u8>()];
+
+        reader.read_exact(&mut magic).map_err(|_| SnapshotError::Magic)?;
+
+        if magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::Magic);
+        }
+
+        reader.read_exact(&mut version)?;
+
+        if version[0] == 0 || version[0] > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version[0]));
+        }
+
+        let mut snapshot = Snapshot::default();
+        let mut id = [0u8; mem::size_of::<u8>()];
+
+        while reader.read_exact(&mut id).is_ok() {
+            let mut len = [0u8; mem::size_of::<u32>()];
+
+            reader.read_exact(&mut len).map_err(|_| SnapshotError::Truncated { section: id[0] })?;
+
+            let len = u32::from_le_bytes(len) as usize;
+            let mut bytes: Vec<u8> = Vec::new();
+
+            // The length comes from the file, so only what is actually there is allocated.
+            reader.take(len as u64).read_to_end(&mut bytes)?;
+
+            if bytes.len() != len {
+                return Err(SnapshotError::Truncated { section: id[0] });
+            }
+
+            let mut section = SectionReader { id: id[0], reader: Cursor::new(bytes) };
+
+            match id[0] {
+                STATE => snapshot.ip = section.int()?,
+                STACK => snapshot.stack = section.ints()?,
+                LOCALS => snapshot.locals = section.ints()?,
+                FRAMES => {
+                    let count = section.int()?;
+
+                    for _ in 0..count {
+                        let target = section.int()?;
+                        let ret = section.int()?;
+                        let base = section.int()? as usize;
+                        let function = Some(section.int()?).filter(|function| *function != NO_FUNCTION);
+                        let floor = section.int()? as usize;
+
+                        snapshot.frames.push(Frame { target, ret, base, function, floor });
+                    }
+                },
+                MEMORY => snapshot.memory = section.ints()?,
+                PROGRAM => snapshot.program = Some(section.reader.into_inner()),
+                _ => {},
+            }
+        }
+
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Inst, StackOp, ExprKind, exec::{Machine, Status}, loader::{self, Program}, syscall::Capture};
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn program() -> Arc<Program> {
+        let instructions = vec![
+            Inst::StackOp(StackOp::Push(1)),
+            Inst::StackOp(StackOp::Push(2)),
+            Inst::BinaryExpr(ExprKind::Add),
+            Inst::StackOp(StackOp::Dump),
+            Inst::Halt,
+        ];
+
+        Arc::new(loader::load(instructions, Vec::new(), Vec::new(), HashMap::new(), Vec::new()).unwrap())
+    }
+
+    fn paused() -> Snapshot {
+        let mut vm = Machine::new(false).stdout(std::io::sink());
+
+        vm.load(program());
+
+        assert_eq!(vm.run(2).unwrap(), Status::Paused);
+
+        vm.snapshot()
+    }
+
+    fn restore(snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let mut vm = Machine::new(false);
+
+        vm.load(program());
+        vm.restore(snapshot)
+    }
+
+    #[test]
+    fn restore_rejects_frames_outside_the_program() {
+        let snapshot = paused();
+
+        assert!(restore(&snapshot).is_ok());
+        assert!(matches!(Machine::new(false).restore(&snapshot), Err(SnapshotError::NoProgram)));
+
+        let frame = Frame { target: 0, ret: 1, base: 0, function: None, floor: 0 };
+        let invalid = [
+            Frame { function: Some(0), ..frame.clone() },
+            Frame { target: 5, ..frame.clone() },
+            Frame { ret: 6, ..frame.clone() },
+            Frame { base: 1, ..frame.clone() },
+            Frame { floor: 3, ..frame.clone() },
+        ];
+
+        for frame in invalid {
+            let snapshot = Snapshot { frames: vec![frame], ..snapshot.clone() };
+
+            assert!(matches!(restore(&snapshot), Err(SnapshotError::InvalidFrame { index: 0 })));
+        }
+
+        let decreasing = Snapshot {
+            locals: vec![0],
+            frames: vec![Frame { base: 1, ..frame.clone() }, frame.clone()],
+            ..snapshot.clone()
+        };
+
+        assert!(matches!(restore(&decreasing), Err(SnapshotError::InvalidFrame { index: 1 })));
+        assert!(matches!(restore(&Snapshot { ip: 5, ..snapshot }), Err(SnapshotError::InvalidIp { ip: 5 })));
+    }
+
+    #[test]
+    fn write_read_restore_round_trip() {
+        let mut snapshot = paused();
+        let mut bytes: Vec<u8> = Vec::new();
+
+        snapshot.program = Some(b"program".to_vec());
+        snapshot.write(&mut bytes).unwrap();
+
+        let read = Snapshot::read(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(read, snapshot);
+
+        let output = Capture::default();
+        let mut vm = Machine::new(false).stdout(output.clone());
+
+        vm.load(program());
+        vm.restore(&read).unwrap();
+
+        assert_eq!(vm.resume().unwrap(), Status::Halted);
+        assert_eq!(output.take(), b"3\n");
+    }
+
+    #[test]
+    fn read_rejects_bad_headers_and_lengths() {
+        assert!(matches!(Snapshot::read(&mut &b"STCK\x01"[..]), Err(SnapshotError::Magic)));
+        assert!(matches!(Snapshot::read(&mut &b"SNAP\x00"[..]), Err(SnapshotError::UnsupportedVersion(0))));
+        assert!(matches!(Snapshot::read(&mut &b"SNAP\x02"[..]), Err(SnapshotError::UnsupportedVersion(2))));
+
+        let huge = [&SNAPSHOT_MAGIC[..], &[SNAPSHOT_VERSION, STACK], &u32::MAX.to_le_bytes(), &[0; 8]].concat();
+
+        assert!(matches!(Snapshot::read(&mut huge.as_slice()), Err(SnapshotError::Truncated { section: STACK })));
+    }
+}
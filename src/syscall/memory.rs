@@ -0,0 +1,274 @@
+use super::Backend;
+
+use nix::libc;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A file opened by a guest.
+#[derive(Debug)]
+struct OpenFile {
+    path: PathBuf,
+    position: usize,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+#[derive(Debug, Default)]
+struct Tree {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+    dirs: BTreeSet<PathBuf>,
+    open: HashMap<u64, OpenFile>,
+    next: u64,
+}
+
+impl Tree {
+    /// Create `dir` and every directory above it.
+    fn create_dirs(&mut self, dir: &Path) {
+        for ancestor in dir.ancestors() {
+            self.dirs.insert(ancestor.to_path_buf());
+        }
+    }
+
+    fn open_file(&mut self, handle: u64) -> io::Result<&mut OpenFile> {
+        self.open
+            .get_mut(&handle)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+}
+
+/// An in-memory filesystem, clones share the same files so they can be preloaded before and
+/// inspected after a run.
+///
+/// Paths are absolute below `/`, relative paths are resolved against it.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFs {
+    tree: Arc<Mutex<Tree>>,
+}
+
+/// Make `path` absolute below `/` and remove `.` and `..` without consulting the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::Prefix(_) => normalized = PathBuf::from("/"),
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            Component::Normal(name) => normalized.push(name),
+        }
+    }
+
+    normalized
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Copy the files and directories below the host directory `dir` to the root of a new
+    /// filesystem.
+    pub fn load_dir(dir: impl AsRef<Path>) -> io::Result<MemoryFs> {
+        let fs = MemoryFs::new();
+        let mut pending: Vec<PathBuf> = vec![dir.as_ref().to_path_buf()];
+
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(&current)? {
+                let path = entry?.path();
+                let inner = Path::new("/").join(path.strip_prefix(dir.as_ref()).unwrap_or(&path));
+
+                if path.is_dir() {
+                    fs.create_dir(&inner);
+                    pending.push(path);
+                } else {
+                    fs.insert_file(&inner, fs::read(&path)?);
+                }
+            }
+        }
+
+        Ok(fs)
+    }
+
+    fn tree(&self) -> MutexGuard<'_, Tree> {
+        self.tree.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Create `dir` and every directory above it.
+    pub fn create_dir(&self, dir: impl AsRef<Path>) {
+        self.tree().create_dirs(&normalize(dir.as_ref()));
+    }
+
+    /// Create or replace the file at `path`, its directories are created as well.
+    pub fn insert_file(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        let path = normalize(path.as_ref());
+        let mut tree = self.tree();
+
+        if let Some(parent) = path.parent() {
+            tree.create_dirs(parent);
+        }
+
+        tree.files.insert(path, contents.into());
+    }
+
+    /// The contents of the file at `path`.
+    pub fn file(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        self.tree().files.get(&normalize(path.as_ref())).cloned()
+    }
+
+    /// The paths of every file.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.tree().files.keys().cloned().collect()
+    }
+}
+
+impl Backend for MemoryFs {
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        Some(normalize(path))
+    }
+
+    fn open(&mut self, path: &Path, flags: i32) -> io::Result<u64> {
+        let path = normalize(path);
+        let mut tree = self.tree();
+
+        if tree.dirs.contains(&path) {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+
+        let mode = flags & libc::O_ACCMODE;
+        let read = mode == libc::O_RDONLY || mode == libc::O_RDWR;
+        let write = mode == libc::O_WRONLY || mode == libc::O_RDWR;
+
+        match tree.files.get_mut(&path) {
+            Some(_) if flags & libc::O_CREAT != 0 && flags & libc::O_EXCL != 0 => {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            },
+            Some(contents) => {
+                if write && flags & libc::O_TRUNC != 0 {
+                    contents.clear();
+                }
+            },
+            None if flags & libc::O_CREAT != 0 => {
+                if !path.parent().map(|parent| tree.dirs.contains(parent) || parent == Path::new("/")).unwrap_or(false) {
+                    return Err(io::Error::from_raw_os_error(libc::ENOENT));
+                }
+
+                tree.files.insert(path.clone(), Vec::new());
+            },
+            None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+
+        let handle = tree.next;
+
+        tree.next += 1;
+        tree.open.insert(handle, OpenFile {
+            path,
+            position: 0,
+            read,
+            write,
+            append: flags & libc::O_APPEND != 0,
+        });
+
+        Ok(handle)
+    }
+
+    fn read(&mut self, handle: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut tree = self.tree();
+        let file = tree.open_file(handle)?;
+
+        if !file.read {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+
+        let (path, position) = (file.path.clone(), file.position);
+        let contents = tree.files.get(&path).map(Vec::as_slice).unwrap_or_default();
+        // Another handle may have truncated the file below the position of this one.
+        let start = position.min(contents.len());
+        let count = (contents.len() - start).min(buf.len());
+
+        buf[..count].copy_from_slice(&contents[start..start + count]);
+        tree.open_file(handle)?.position += count;
+
+        Ok(count)
+    }
+
+    fn write(&mut self, handle: u64, buf: &[u8]) -> io::Result<usize> {
+        let mut tree = self.tree();
+        let file = tree.open_file(handle)?;
+
+        if !file.write {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+
+        let (path, position, append) = (file.path.clone(), file.position, file.append);
+        let contents = tree.files.entry(path).or_default();
+        let start = if append { contents.len() } else { position };
+
+        if contents.len() < start + buf.len() {
+            contents.resize(start + buf.len(), 0);
+        }
+
+        contents[start..start + buf.len()].copy_from_slice(buf);
+        tree.open_file(handle)?.position = start + buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn close(&mut self, handle: u64) -> io::Result<()> {
+        self.tree()
+            .open
+            .remove(&handle)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_after_truncate_by_another_handle() {
+        let mut fs = MemoryFs::new();
+
+        let first = fs.open(Path::new("/file"), libc::O_RDWR | libc::O_CREAT).unwrap();
+
+        assert_eq!(fs.write(first, b"0123456789").unwrap(), 10);
+
+        let second = fs.open(Path::new("/file"), libc::O_WRONLY | libc::O_TRUNC).unwrap();
+        let mut buf = [0u8; 4];
+
+        assert_eq!(fs.read(first, &mut buf).unwrap(), 0);
+
+        fs.write(second, b"ab").unwrap();
+
+        assert_eq!(fs.read(first, &mut buf).unwrap(), 0);
+        assert_eq!(fs.file("/file").unwrap(), b"ab");
+    }
+
+    #[test]
+    fn read_write_and_inspect() {
+        let mut fs = MemoryFs::new();
+
+        fs.insert_file("/dir/in.txt", "hello");
+
+        let input = fs.open(Path::new("/dir/../dir/in.txt"), libc::O_RDONLY).unwrap();
+        let output = fs.open(Path::new("/dir/out.txt"), libc::O_WRONLY | libc::O_CREAT).unwrap();
+        let mut buf = [0u8; 16];
+        let count = fs.read(input, &mut buf).unwrap();
+
+        fs.write(output, &buf[..count]).unwrap();
+
+        assert_eq!(fs.file("/dir/out.txt").unwrap(), b"hello");
+        assert!(fs.write(input, b"x").is_err());
+        assert!(fs.open(Path::new("/missing/file"), libc::O_WRONLY | libc::O_CREAT).is_err());
+        assert!(fs.open(Path::new("/dir"), libc::O_RDONLY).is_err());
+        assert!(fs.open(Path::new("/dir/in.txt"), libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL).is_err());
+    }
+}
@@ -1,9 +1,24 @@
 use crate::exec::Value;
 
-use nix::unistd;
 use nix::libc;
 
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
+mod memory;
+mod os;
+
+pub use memory::MemoryFs;
+pub use os::OsBackend;
+
+/// The value pushed in place of the result of a syscall that failed or was denied.
+pub const FAILED: u32 = u32::MAX;
+
+/// The amount of buffered standard output that is written out without waiting for a flush.
+const OUTPUT_BUFFER: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Syscall {
     Read,
     Write,
@@ -25,52 +40,427 @@ impl From<u32> for Syscall {
     }
 }
 
-fn write_temp(temp: &[u8], buf: &mut [Value]) {
-    for (index, byte) in temp.iter().enumerate() {
-        buf[index] = Value::Int(*byte as u32);
+/// Where the files a guest opens live, the standard streams are set separately with [`Stdio`].
+pub trait Backend: Send {
+    /// Resolve `path` to the absolute path `open` would open, used to check the policy.
+    fn resolve(&self, path: &Path) -> Option<PathBuf>;
+
+    /// Open the file at `path` with the flags of `open(2)`, returns the handle the other
+    /// methods take.
+    fn open(&mut self, path: &Path, flags: i32) -> io::Result<u64>;
+
+    fn read(&mut self, handle: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn write(&mut self, handle: u64, buf: &[u8]) -> io::Result<usize>;
+
+    fn close(&mut self, handle: u64) -> io::Result<()>;
+}
+
+/// The streams a guest reads and writes as the descriptors 0, 1 and 2, `Dump` prints to
+/// `stdout` as well.
+pub struct Stdio {
+    pub stdin: Box<dyn Read + Send>,
+    pub stdout: Box<dyn Write + Send>,
+    pub stderr: Box<dyn Write + Send>,
+}
+
+impl Default for Stdio {
+    /// The standard streams of the host.
+    fn default() -> Stdio {
+        Stdio {
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        }
     }
 }
 
-fn value_to_u8(values: &[Value]) -> Vec<u8> {
-    values
-        .iter()
-        .map(|value| value.as_int().clamp(0, 255) as u8)
-        .collect::<Vec<u8>>()
+/// A sink collecting everything written to it, clones share the same buffer.
+#[derive(Clone, Default)]
+pub(crate) struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub(crate) fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
 }
 
-pub fn read(fd: usize, buf: &mut [Value]) -> Result<(), Box<dyn std::error::Error>> {
-    let mut temp = vec![0u8; buf.len()];
-    unistd::read(fd as i32, &mut temp)?;
-    write_temp(&temp, buf);
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).extend_from_slice(buf);
+        Ok(buf.len())
+    }
 
-    Ok(())
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a guest descriptor refers to.
+#[derive(Clone, Copy, Debug)]
+enum Handle {
+    /// A standard stream, closing it only removes it from the table.
+    Stdin,
+    Stdout,
+    Stderr,
+    /// A file the guest opened through the backend.
+    File(u64),
 }
 
-pub fn write(fd: usize, buf: &[Value]) -> Result<(), Box<dyn std::error::Error>> {
-    unistd::write(fd as i32, &value_to_u8(buf))?;
+fn bad_descriptor() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
 
-    Ok(())
+/// Maps the descriptors a guest uses to the standard streams and the files it opened through
+/// the backend, so a guest can only use what it opened itself.
+///
+/// Standard output is buffered until [`FdTable::flush`] or until the buffer fills up, it is
+/// flushed before reading standard input so prompts show up.
+pub(crate) struct FdTable {
+    handles: Vec<Option<Handle>>,
+    backend: Box<dyn Backend>,
+    pub(crate) stdio: Stdio,
+    output: Vec<u8>,
 }
 
-pub fn open(filename: &str, flags: usize) -> Result<i32, i32> {
-    let status = unsafe { libc::open(&(filename.as_bytes()[0] as i8) as *const i8, flags as i32) };
+impl FdTable {
+    /// A table holding the standard streams as 0, 1 and 2.
+    pub(crate) fn new(backend: Box<dyn Backend>, stdio: Stdio) -> FdTable {
+        FdTable {
+            handles: vec![Some(Handle::Stdin), Some(Handle::Stdout), Some(Handle::Stderr)],
+            backend,
+            stdio,
+            output: Vec::with_capacity(OUTPUT_BUFFER),
+        }
+    }
+
+    pub(crate) fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
+    }
+
+    /// Replace the backend, files opened through the previous one are closed.
+    pub(crate) fn set_backend(&mut self, backend: Box<dyn Backend>) {
+        for handle in &mut self.handles {
+            if let Some(Handle::File(_)) = handle {
+                *handle = None;
+            }
+        }
+
+        self.backend = backend;
+    }
+
+    /// Close the files a previous run left open and hold only the standard streams as 0, 1
+    /// and 2 again, buffered output is written out first.
+    pub(crate) fn reset(&mut self) {
+        let _ = self.flush();
+
+        for handle in self.handles.drain(..).flatten() {
+            if let Handle::File(handle) = handle {
+                let _ = self.backend.close(handle);
+            }
+        }
+
+        self.handles.extend([Some(Handle::Stdin), Some(Handle::Stdout), Some(Handle::Stderr)]);
+    }
+
+    fn get(&self, fd: u32) -> io::Result<Handle> {
+        self.handles
+            .get(fd as usize)
+            .copied()
+            .flatten()
+            .ok_or_else(bad_descriptor)
+    }
+
+    /// Open a file through the backend at the lowest free guest descriptor.
+    pub(crate) fn open(&mut self, path: &Path, flags: i32) -> io::Result<u32> {
+        let handle = Some(Handle::File(self.backend.open(path, flags)?));
+
+        match self.handles.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.handles[fd] = handle;
+                Ok(fd as u32)
+            },
+            None => {
+                self.handles.push(handle);
+                Ok(self.handles.len() as u32 - 1)
+            },
+        }
+    }
+
+    /// Write the buffered output to standard output.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        if !self.output.is_empty() {
+            let written = self.stdio.stdout.write_all(&self.output);
+
+            self.output.clear();
+            written?;
+        }
+
+        self.stdio.stdout.flush()
+    }
+
+    fn flush_full(&mut self) -> io::Result<()> {
+        if self.output.len() >= OUTPUT_BUFFER {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Print `value` on its own line to the buffered standard output.
+    pub(crate) fn dump(&mut self, value: Value) -> io::Result<()> {
+        writeln!(self.output, "{value}")?;
+        self.flush_full()
+    }
+
+    /// Read from `fd` into memory cells, one byte per cell.
+    pub(crate) fn read(&mut self, fd: u32, buf: &mut [Value]) -> io::Result<usize> {
+        let mut temp = vec![0u8; buf.len()];
+
+        let count = match self.get(fd)? {
+            Handle::Stdin => {
+                // A failed flush should not keep the guest from reading its input.
+                let _ = self.flush();
+
+                self.stdio.stdin.read(&mut temp)?
+            },
+            Handle::Stdout | Handle::Stderr => return Err(bad_descriptor()),
+            Handle::File(handle) => self.backend.read(handle, &mut temp)?,
+        };
+
+        for (cell, byte) in buf.iter_mut().zip(&temp[..count]) {
+            *cell = Value::Int(*byte as u32);
+        }
+
+        Ok(count)
+    }
+
+    /// Write memory cells to `fd`, one byte per cell.
+    pub(crate) fn write(&mut self, fd: u32, buf: &[Value]) -> io::Result<usize> {
+        let bytes: Vec<u8> = buf
+            .iter()
+            .map(|value| value.as_int().clamp(0, 255) as u8)
+            .collect();
+
+        match self.get(fd)? {
+            Handle::Stdin => Err(bad_descriptor()),
+            Handle::Stdout => {
+                self.output.extend_from_slice(&bytes);
+                self.flush_full()?;
+
+                Ok(bytes.len())
+            },
+            Handle::Stderr => {
+                // Output written before has to show up before the error when both go to a terminal.
+                let _ = self.flush();
+
+                let count = self.stdio.stderr.write(&bytes)?;
+
+                self.stdio.stderr.flush()?;
 
-    return if status < 0 {
-        Err(status)
-    } else {
-        Ok(status)
-    };
+                Ok(count)
+            },
+            Handle::File(handle) => self.backend.write(handle, &bytes),
+        }
+    }
+
+    /// Remove the guest descriptor `fd`, the descriptor can be reused afterwards.
+    pub(crate) fn close(&mut self, fd: u32) -> io::Result<()> {
+        let handle = self.get(fd)?;
+
+        self.handles[fd as usize] = None;
+
+        match handle {
+            Handle::File(handle) => self.backend.close(handle),
+            _ => Ok(()),
+        }
+    }
 }
 
-pub fn close(fd: usize) -> Result<i32 , i32> {
-    let status = unsafe { libc::close(fd as i32) };
+/// Which syscalls a machine may make and which files it may open.
+#[derive(Clone, Debug)]
+pub struct SyscallPolicy {
+    allowed: Vec<Syscall>,
+    /// The directories files can be opened for reading and writing in, `None` allows any path.
+    paths: Option<(Vec<PathBuf>, Vec<PathBuf>)>,
+}
 
-    return if status < 0 {
-        Err(status)
-    } else {
-        Ok(status)
-    };
+impl Default for SyscallPolicy {
+    fn default() -> SyscallPolicy {
+        SyscallPolicy::allow_all()
+    }
 }
 
+impl SyscallPolicy {
+    /// Allow every syscall on every path.
+    pub fn allow_all() -> SyscallPolicy {
+        SyscallPolicy {
+            allowed: vec![Syscall::Read, Syscall::Write, Syscall::Open, Syscall::Close],
+            paths: None,
+        }
+    }
+
+    /// Deny every syscall.
+    pub fn deny_all() -> SyscallPolicy {
+        SyscallPolicy {
+            allowed: Vec::new(),
+            paths: Some((Vec::new(), Vec::new())),
+        }
+    }
+
+    /// Allow a syscall, `Open` is still restricted to the allowed directories if there are any.
+    pub fn allow(mut self, syscall: Syscall) -> SyscallPolicy {
+        if syscall != Syscall::Unknown && !self.allowed.contains(&syscall) {
+            self.allowed.push(syscall);
+        }
+
+        self
+    }
+
+    /// Allow opening files below `dir` for reading, reading and closing.
+    ///
+    /// Once any directory is allowed files outside of the allowed directories can not be opened.
+    pub fn allow_read(self, dir: impl AsRef<Path>) -> SyscallPolicy {
+        let mut policy = self.allow(Syscall::Open).allow(Syscall::Read).allow(Syscall::Close);
+
+        policy.paths.get_or_insert_with(Default::default).0.push(dir.as_ref().to_path_buf());
+        policy
+    }
+
+    /// Allow opening files below `dir` for writing, writing and closing.
+    ///
+    /// Once any directory is allowed files outside of the allowed directories can not be opened.
+    pub fn allow_write(self, dir: impl AsRef<Path>) -> SyscallPolicy {
+        let mut policy = self.allow(Syscall::Open).allow(Syscall::Write).allow(Syscall::Close);
+
+        policy.paths.get_or_insert_with(Default::default).1.push(dir.as_ref().to_path_buf());
+        policy
+    }
+
+    pub fn allows(&self, syscall: Syscall) -> bool {
+        self.allowed.contains(&syscall)
+    }
+
+    /// Whether the file at `path` may be opened with `flags`, paths are resolved by `backend`.
+    pub fn allows_open(&self, backend: &dyn Backend, path: &Path, flags: i32) -> bool {
+        if !self.allows(Syscall::Open) {
+            return false;
+        }
+
+        let Some((read, write)) = &self.paths else {
+            return true;
+        };
+
+        let Some(path) = backend.resolve(path) else {
+            return false;
+        };
+
+        let inside = |dirs: &[PathBuf]| dirs
+            .iter()
+            .filter_map(|dir| backend.resolve(dir))
+            .any(|dir| path.starts_with(dir));
 
+        let mode = flags & libc::O_ACCMODE;
+        let reads = mode == libc::O_RDONLY || mode == libc::O_RDWR;
+        let writes = mode != libc::O_RDONLY || flags & (libc::O_CREAT | libc::O_TRUNC | libc::O_APPEND) != 0;
 
+        (!reads || inside(read)) && (!writes || inside(write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    /// A fresh directory with the subdirectories `a`, `ab` and `b`.
+    fn sandbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("stacked-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+
+        for sub in ["a", "ab", "b"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+
+        fs::write(dir.join("a/file"), b"a").unwrap();
+        fs::write(dir.join("ab/file"), b"ab").unwrap();
+        fs::write(dir.join("b/secret"), b"b").unwrap();
+
+        dir
+    }
+
+    const READ: i32 = libc::O_RDONLY;
+    const CREATE: i32 = libc::O_WRONLY | libc::O_CREAT;
+
+    #[test]
+    fn stdout_is_flushed_before_writing_to_stderr() {
+        let capture = Capture::default();
+        let mut fds = FdTable::new(Box::new(MemoryFs::new()), Stdio {
+            stdin: Box::new(io::empty()),
+            stdout: Box::new(capture.clone()),
+            stderr: Box::new(capture.clone()),
+        });
+        let text = |text: &str| text.bytes().map(|byte| Value::Int(byte as u32)).collect::<Vec<Value>>();
+
+        fds.write(1, &text("out ")).unwrap();
+        fds.write(2, &text("err ")).unwrap();
+        fds.write(1, &text("out")).unwrap();
+        fds.flush().unwrap();
+
+        assert_eq!(capture.take(), b"out err out");
+    }
+
+    #[test]
+    fn parent_dirs_can_not_leave_the_sandbox() {
+        let dir = sandbox("parent");
+        let policy = SyscallPolicy::deny_all().allow_read(dir.join("a"));
+        let backend = OsBackend::new();
+
+        assert!(policy.allows_open(&backend, &dir.join("a/file"), READ));
+        assert!(policy.allows_open(&backend, &dir.join("a/../a/file"), READ));
+        assert!(!policy.allows_open(&backend, &dir.join("a/../b/secret"), READ));
+        assert!(!policy.allows_open(&backend, &dir.join("a/../b/missing"), READ));
+        assert!(!policy.allows_open(&backend, &dir.join("a/file"), CREATE));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sibling_dirs_sharing_a_prefix_are_outside() {
+        let dir = sandbox("sibling");
+        let policy = SyscallPolicy::deny_all().allow_read(dir.join("a")).allow_write(dir.join("a"));
+        let backend = OsBackend::new();
+
+        assert!(policy.allows_open(&backend, &dir.join("a/new"), CREATE));
+        assert!(!policy.allows_open(&backend, &dir.join("ab/file"), READ));
+        assert!(!policy.allows_open(&backend, &dir.join("ab/new"), CREATE));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlinks_are_checked_by_their_target() {
+        let dir = sandbox("symlink");
+        let policy = SyscallPolicy::deny_all().allow_read(dir.join("a")).allow_write(dir.join("a"));
+        let backend = OsBackend::new();
+
+        symlink(dir.join("b/secret"), dir.join("a/secret")).unwrap();
+        symlink(dir.join("b/missing"), dir.join("a/dangling")).unwrap();
+        symlink("../b", dir.join("a/up")).unwrap();
+        symlink("file", dir.join("a/inside")).unwrap();
+        symlink("new", dir.join("a/dangling-inside")).unwrap();
+        symlink("loop", dir.join("a/loop")).unwrap();
+
+        assert!(!policy.allows_open(&backend, &dir.join("a/secret"), READ));
+        assert!(!policy.allows_open(&backend, &dir.join("a/dangling"), CREATE));
+        assert!(!policy.allows_open(&backend, &dir.join("a/up/missing"), CREATE));
+        assert!(!policy.allows_open(&backend, &dir.join("a/loop"), CREATE));
+        assert!(policy.allows_open(&backend, &dir.join("a/inside"), READ));
+        assert!(policy.allows_open(&backend, &dir.join("a/dangling-inside"), CREATE));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
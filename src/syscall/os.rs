@@ -0,0 +1,96 @@
+use super::Backend;
+
+use nix::unistd;
+use nix::libc;
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// The most symlinks followed while resolving a path, like `SYMLOOP_MAX` on Linux.
+const MAX_LINKS: usize = 40;
+
+/// Opens files on the real filesystem of the host.
+#[derive(Debug, Default)]
+pub struct OsBackend {
+    /// The opened files by their host descriptor, they are closed when the backend is dropped.
+    files: HashMap<u64, OwnedFd>,
+}
+
+impl OsBackend {
+    pub fn new() -> OsBackend {
+        OsBackend::default()
+    }
+
+    fn file(&self, handle: u64) -> io::Result<&OwnedFd> {
+        self.files
+            .get(&handle)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+}
+
+impl Backend for OsBackend {
+    /// Resolve symlinks and `..`, the file itself does not have to exist as long as its
+    /// directory does. A dangling symlink resolves to its target since `open` with `O_CREAT`
+    /// creates the file it points to.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let mut path = path.to_path_buf();
+
+        for _ in 0..MAX_LINKS {
+            if let Ok(path) = fs::canonicalize(&path) {
+                return Some(path);
+            }
+
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+
+            let parent = fs::canonicalize(parent).ok()?;
+            let file = parent.join(path.file_name()?);
+
+            match fs::read_link(&file) {
+                Ok(target) => path = parent.join(target),
+                Err(_) => return Some(file),
+            }
+        }
+
+        None
+    }
+
+    fn open(&mut self, path: &Path, flags: i32) -> io::Result<u64> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let status = unsafe { libc::open(path.as_ptr(), flags, 0o666) };
+
+        if status < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: the descriptor was just opened and nothing else owns it.
+        let file = unsafe { OwnedFd::from_raw_fd(status) };
+
+        self.files.insert(status as u64, file);
+
+        Ok(status as u64)
+    }
+
+    fn read(&mut self, handle: u64, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(unistd::read(self.file(handle)?.as_raw_fd(), buf)?)
+    }
+
+    fn write(&mut self, handle: u64, buf: &[u8]) -> io::Result<usize> {
+        Ok(unistd::write(self.file(handle)?.as_raw_fd(), buf)?)
+    }
+
+    fn close(&mut self, handle: u64) -> io::Result<()> {
+        let file = self.files
+            .remove(&handle)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+
+        Ok(unistd::close(file.into_raw_fd())?)
+    }
+}
@@ -0,0 +1,489 @@
+use crate::{Inst, Jump, StackOp, MemOp, LocalOp, Function, syscall::Syscall};
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Maximum amount of passes used to compute the stack effect of called functions.
+const MAX_PASSES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    UnknownLabel { ip: usize, label: u32 },
+    DuplicateLabel { ip: usize, label: u32, first: usize },
+    StackUnderflow { ip: usize },
+    InconsistentStack { ip: usize, expected: i64, found: i64 },
+    UnknownSyscall { ip: usize, number: u32 },
+    ReturnOutsideCall { ip: usize },
+    UnknownFunction { label: u32, name: String },
+    /// A function consumes more values than it declares parameters.
+    Arguments { name: String, params: u32, found: i64 },
+    /// A function leaves a different amount of values than it declares results.
+    Results { name: String, results: u32, found: i64 },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::UnknownLabel { ip, label } => write!(f, "instruction {ip}: unknown label `{label}`"),
+            VerifyError::DuplicateLabel { ip, label, first } => write!(f, "instruction {ip}: label `{label}` is already defined at instruction {first}"),
+            VerifyError::StackUnderflow { ip } => write!(f, "instruction {ip}: stackunderflow"),
+            VerifyError::InconsistentStack { ip, expected, found } => write!(f, "instruction {ip}: inconsistent stack depth, expected {expected} but found {found}"),
+            VerifyError::UnknownSyscall { ip, number } => write!(f, "instruction {ip}: unknown syscall number `{number}`"),
+            VerifyError::ReturnOutsideCall { ip } => write!(f, "instruction {ip}: return is reachable outside of a call"),
+            VerifyError::UnknownFunction { label, name } => write!(f, "function `{name}`: unknown label `{label}`"),
+            VerifyError::Arguments { name, params, found } => write!(f, "function `{name}`: declares {params} parameters but consumes {found}"),
+            VerifyError::Results { name, results, found } => write!(f, "function `{name}`: declares {results} results but returns {found}"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// The stack effect of a function, relative to the depth when it was called.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Summary {
+    /// The lowest depth reached, the negation is the amount of values the function consumes.
+    min: i64,
+    /// The depth when the function returns.
+    net: i64,
+}
+
+struct Analysis<'a> {
+    instructions: &'a [Inst],
+    labels: &'a HashMap<u32, usize>,
+    summaries: &'a HashMap<u32, Option<Summary>>,
+    /// Labels of the functions whose reference is pushed with `PushFunc`.
+    references: &'a [u32],
+    errors: Vec<VerifyError>,
+}
+
+impl<'a> Analysis<'a> {
+    fn report(&mut self, err: VerifyError) {
+        if !self.errors.contains(&err) {
+            self.errors.push(err);
+        }
+    }
+
+    /// The amount of values the syscall at `ip` pops, `Ok(None)` if its number is not pushed
+    /// right before it.
+    fn syscall_effect(&self, ip: usize) -> Result<Option<i64>, u32> {
+        let number = match ip.checked_sub(1).map(|prev| &self.instructions[prev]) {
+            Some(Inst::StackOp(StackOp::Push(number))) => *number,
+            _ => return Ok(None),
+        };
+
+        match Syscall::from(number) {
+            Syscall::Read | Syscall::Write => Ok(Some(4)),
+            Syscall::Open => Ok(Some(3)),
+            Syscall::Close => Ok(Some(2)),
+            Syscall::Unknown => Err(number),
+        }
+    }
+
+    /// The stack effect of the call at `ip`, if it is known.
+    ///
+    /// An indirect call directly following a `PushFunc` calls that function, otherwise it can
+    /// call any referenced function and is only known if all of them have the same effect.
+    fn callee(&self, ip: usize) -> Option<Summary> {
+        let summary = |label: &u32| self.labels
+            .get(label)
+            .and_then(|_| self.summaries.get(label).copied().flatten());
+
+        match (&self.instructions[ip], ip.checked_sub(1).map(|prev| &self.instructions[prev])) {
+            (Inst::Call(label), _) | (Inst::CallIndirect, Some(Inst::StackOp(StackOp::PushFunc(label)))) => summary(label),
+            _ => {
+                let mut summaries = self.references.iter().map(summary);
+                let first = summaries.next()??;
+
+                summaries.all(|other| other == Some(first)).then_some(first)
+            },
+        }
+    }
+
+    /// Walk every path from `entry`, returns the summary of the code if it can return.
+    ///
+    /// Paths are followed past instructions whose stack effect is not known with an unknown
+    /// depth, the depth is only checked again where such a path merges with a known one. The
+    /// summary only comes from the returns reached with a known depth.
+    fn walk(&mut self, entry: usize, in_call: bool) -> Option<Summary> {
+        let mut depths: HashMap<usize, Option<i64>> = HashMap::new();
+        let mut pending: Vec<(usize, Option<i64>)> = vec![(entry, Some(0))];
+        let mut summary: Option<Summary> = None;
+        let mut min = 0;
+
+        while let Some((ip, depth)) = pending.pop() {
+            if ip >= self.instructions.len() {
+                continue;
+            }
+
+            match (depths.get(&ip), depth) {
+                (Some(Some(expected)), Some(found)) if *expected != found => {
+                    self.report(VerifyError::InconsistentStack { ip, expected: *expected, found });
+                    continue;
+                },
+                // A known depth is walked again where only an unknown one was seen.
+                (Some(None), Some(_)) | (None, _) => {},
+                (Some(_), _) => continue,
+            }
+
+            depths.insert(ip, depth);
+
+            let effect = match &self.instructions[ip] {
+                Inst::BinaryExpr(_) => (2, 1),
+                Inst::StackOp(op) => match op {
+                    StackOp::Push(_) | StackOp::PushData(_) | StackOp::PushFunc(_) => (0, 1),
+                    StackOp::Pop | StackOp::Dump => (1, 0),
+                    StackOp::Dup => (1, 2),
+                    StackOp::Swap => (2, 2),
+                    StackOp::Rot => (3, 3),
+                    StackOp::Cmp => (2, 1),
+                    StackOp::Over | StackOp::Tuck => (2, 3),
+                    StackOp::Nip => (2, 1),
+                    StackOp::Pick(index) => (*index as i64 + 1, *index as i64 + 2),
+                    StackOp::Roll(index) => (*index as i64 + 1, *index as i64 + 1),
+                    StackOp::Depth => (0, 1),
+                },
+                Inst::MemOp(op) => match op {
+                    MemOp::InsertStr(_) => (1, 0),
+                    MemOp::InsertBytes(_) => (1, 1),
+                    MemOp::Load => (1, 1),
+                    MemOp::Store => (2, 0),
+                },
+                Inst::LocalOp(op) => match op {
+                    LocalOp::Enter(_) | LocalOp::Leave => (0, 0),
+                    LocalOp::Load(_) => (0, 1),
+                    LocalOp::Store(_) => (1, 0),
+                },
+                Inst::Jump(Jump::Unconditional, _) | Inst::Label(_) | Inst::Call(_) | Inst::Return | Inst::Halt | Inst::Flush => (0, 0),
+                Inst::Jump(..) | Inst::JumpTable(_) | Inst::CallIndirect => (1, 0),
+                // Host functions are registered at runtime, their effect is not known here.
+                Inst::HostCall(_) => {
+                    pending.push((ip + 1, None));
+                    continue;
+                },
+                Inst::Syscall => match self.syscall_effect(ip) {
+                    Ok(Some(pops)) => (pops, 1),
+                    Ok(None) => {
+                        pending.push((ip + 1, None));
+                        continue;
+                    },
+                    Err(number) => {
+                        self.report(VerifyError::UnknownSyscall { ip, number });
+                        continue;
+                    },
+                },
+            };
+
+            let depth = match depth {
+                Some(depth) => {
+                    let (pops, pushes) = effect;
+
+                    if depth - pops < 0 && !in_call {
+                        self.report(VerifyError::StackUnderflow { ip });
+                        continue;
+                    }
+
+                    min = min.min(depth - pops);
+
+                    Some(depth - pops + pushes)
+                },
+                None => None,
+            };
+
+            match &self.instructions[ip] {
+                Inst::Jump(jump, label) => {
+                    if let Some(target) = self.labels.get(label) {
+                        pending.push((*target, depth));
+                    }
+
+                    if *jump != Jump::Unconditional {
+                        pending.push((ip + 1, depth));
+                    }
+                },
+                Inst::JumpTable(labels) => {
+                    for label in labels {
+                        if let Some(target) = self.labels.get(label) {
+                            pending.push((*target, depth));
+                        }
+                    }
+
+                    pending.push((ip + 1, depth));
+                },
+                Inst::Call(_) | Inst::CallIndirect => {
+                    if let Some(callee) = self.callee(ip) {
+                        let Some(depth) = depth else {
+                            pending.push((ip + 1, None));
+                            continue;
+                        };
+
+                        if depth + callee.min < 0 && !in_call {
+                            self.report(VerifyError::StackUnderflow { ip });
+                            continue;
+                        }
+
+                        min = min.min(depth + callee.min);
+
+                        pending.push((ip + 1, Some(depth + callee.net)));
+                    } else {
+                        // The callee may be recursive, make a host call or not be known at all.
+                        pending.push((ip + 1, None));
+                    }
+                },
+                Inst::Return => {
+                    if !in_call {
+                        self.report(VerifyError::ReturnOutsideCall { ip });
+                    } else if let Some(depth) = depth {
+                        if let Some(previous) = summary {
+                            if previous.net != depth {
+                                self.report(VerifyError::InconsistentStack { ip, expected: previous.net, found: depth });
+                            }
+                        } else {
+                            summary = Some(Summary { min: 0, net: depth });
+                        }
+                    }
+                },
+                Inst::Halt => {},
+                _ => pending.push((ip + 1, depth)),
+            }
+        }
+
+        summary.map(|summary| Summary { min, ..summary })
+    }
+}
+
+/// Statically check a parsed program before executing it.
+///
+/// Every jump and call target has to exist, labels can only be defined once, the stack depth
+/// has to be the same on every path reaching an instruction and may never become negative,
+/// and `Return` can only be reached from code that was called. Code following a host call, a
+/// syscall whose number is not pushed right before it or a call whose effect is not known is
+/// checked without its stack depth. A function that only returns past such an instruction has
+/// no known effect unless it is declared in the function table. Calls to functions of the
+/// function table use the declared signature, the body of every declared function has to
+/// match it.
+pub fn verify(instructions: &[Inst], table: &[Function]) -> Result<(), Vec<VerifyError>> {
+    let mut errors: Vec<VerifyError> = Vec::new();
+    let mut labels: HashMap<u32, usize> = HashMap::new();
+
+    for (ip, inst) in instructions.iter().enumerate() {
+        if let Inst::Label(label) = inst {
+            if let Some(first) = labels.get(label) {
+                errors.push(VerifyError::DuplicateLabel { ip, label: *label, first: *first });
+            } else {
+                labels.insert(*label, ip);
+            }
+        }
+    }
+
+    let mut functions: Vec<u32> = Vec::new();
+    let mut references: Vec<u32> = Vec::new();
+
+    for (ip, inst) in instructions.iter().enumerate() {
+        for label in inst.targets() {
+            if !labels.contains_key(label) {
+                errors.push(VerifyError::UnknownLabel { ip, label: *label });
+                continue;
+            }
+
+            if matches!(inst, Inst::Call(_) | Inst::StackOp(StackOp::PushFunc(_))) && !functions.contains(label) {
+                functions.push(*label);
+            }
+
+            if matches!(inst, Inst::StackOp(StackOp::PushFunc(_))) && !references.contains(label) {
+                references.push(*label);
+            }
+        }
+    }
+
+    let mut declared: HashMap<u32, &Function> = HashMap::new();
+
+    for function in table {
+        if !labels.contains_key(&function.label) {
+            errors.push(VerifyError::UnknownFunction { label: function.label, name: function.name.clone() });
+        } else {
+            if !functions.contains(&function.label) {
+                functions.push(function.label);
+            }
+
+            declared.insert(function.label, function);
+        }
+    }
+
+    let mut summaries: HashMap<u32, Option<Summary>> = functions
+        .iter()
+        .map(|label| (*label, declared.get(label).map(|function| Summary {
+            min: -(function.params as i64),
+            net: function.results as i64 - function.params as i64,
+        })))
+        .collect();
+
+    for _ in 0..MAX_PASSES {
+        let mut next = summaries.clone();
+
+        for label in functions.iter().filter(|label| !declared.contains_key(label)) {
+            let mut analysis = Analysis { instructions, labels: &labels, summaries: &summaries, references: &references, errors: Vec::new() };
+
+            next.insert(*label, analysis.walk(labels[label], true));
+        }
+
+        if next == summaries {
+            break;
+        }
+
+        summaries = next;
+    }
+
+    let mut analysis = Analysis { instructions, labels: &labels, summaries: &summaries, references: &references, errors };
+
+    analysis.walk(0, false);
+
+    for label in &functions {
+        let summary = analysis.walk(labels[label], true);
+
+        if let (Some(function), Some(summary)) = (declared.get(label), summary) {
+            let params = function.params as i64;
+
+            if -summary.min > params {
+                analysis.report(VerifyError::Arguments { name: function.name.clone(), params: function.params, found: -summary.min });
+            } else if summary.net + params != function.results as i64 {
+                analysis.report(VerifyError::Results { name: function.name.clone(), results: function.results, found: summary.net + params });
+            }
+        }
+    }
+
+    if analysis.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(analysis.errors)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(value: u32) -> Inst {
+        Inst::StackOp(StackOp::Push(value))
+    }
+
+    fn function(label: u32, params: u32, results: u32) -> Function {
+        Function { label, name: format!("f{label}"), params, results, locals: 0 }
+    }
+
+    #[test]
+    fn stack_underflow() {
+        assert_eq!(verify(&[push(1), push(2), Inst::BinaryExpr(crate::ExprKind::Add), Inst::Halt], &[]), Ok(()));
+        assert_eq!(
+            verify(&[push(1), Inst::BinaryExpr(crate::ExprKind::Add), Inst::Halt], &[]),
+            Err(vec![VerifyError::StackUnderflow { ip: 1 }]),
+        );
+    }
+
+    #[test]
+    fn merge_depths() {
+        let merge = |value: Inst| vec![
+            push(0),
+            Inst::Jump(Jump::Equal, 0),
+            push(1),
+            value,
+            Inst::Label(0),
+            Inst::Halt,
+        ];
+
+        assert_eq!(verify(&merge(Inst::StackOp(StackOp::Pop)), &[]), Ok(()));
+        assert_eq!(
+            verify(&merge(Inst::StackOp(StackOp::Dup)), &[]),
+            Err(vec![VerifyError::InconsistentStack { ip: 4, expected: 2, found: 0 }]),
+        );
+    }
+
+    #[test]
+    fn function_summaries() {
+        let program = |body: Vec<Inst>| {
+            let mut instructions = vec![push(1), push(2), Inst::Call(0), Inst::StackOp(StackOp::Dump), Inst::Halt, Inst::Label(0)];
+
+            instructions.extend(body);
+            instructions.push(Inst::Return);
+            instructions
+        };
+        let add = program(vec![Inst::BinaryExpr(crate::ExprKind::Add)]);
+        let pop = program(vec![Inst::StackOp(StackOp::Pop), Inst::StackOp(StackOp::Pop)]);
+        let three = program(vec![Inst::StackOp(StackOp::Pop), Inst::StackOp(StackOp::Pop), Inst::StackOp(StackOp::Pop)]);
+
+        assert_eq!(verify(&add, &[]), Ok(()));
+        assert_eq!(verify(&add, &[function(0, 2, 1)]), Ok(()));
+        assert_eq!(verify(&pop, &[]), Err(vec![VerifyError::StackUnderflow { ip: 3 }]));
+        assert_eq!(
+            verify(&add, &[function(0, 2, 2)]),
+            Err(vec![VerifyError::Results { name: "f0".into(), results: 2, found: 1 }]),
+        );
+        assert_eq!(
+            verify(&three, &[function(0, 2, 1)]),
+            Err(vec![VerifyError::Arguments { name: "f0".into(), params: 2, found: 3 }]),
+        );
+    }
+
+    #[test]
+    fn code_after_unknown_calls_is_checked() {
+        assert_eq!(
+            verify(&[Inst::Call(0), Inst::Return, Inst::Label(0), Inst::HostCall(0), Inst::Return], &[]),
+            Err(vec![VerifyError::ReturnOutsideCall { ip: 1 }]),
+        );
+
+        // The referenced functions have different effects.
+        let indirect = vec![
+            Inst::StackOp(StackOp::PushFunc(0)), Inst::StackOp(StackOp::PushFunc(1)), Inst::StackOp(StackOp::Pop),
+            Inst::CallIndirect, Inst::Return,
+            Inst::Label(0), Inst::Return,
+            Inst::Label(1), push(1), Inst::Return,
+        ];
+
+        assert_eq!(verify(&indirect, &[]), Err(vec![VerifyError::ReturnOutsideCall { ip: 4 }]));
+
+        // Recursive functions still get the effect of their base case.
+        let recursive = vec![
+            push(3), Inst::Call(0), Inst::StackOp(StackOp::Dump), Inst::StackOp(StackOp::Dump), Inst::Halt,
+            Inst::Label(0),
+            Inst::StackOp(StackOp::Dup), Inst::Jump(Jump::Equal, 1),
+            push(1), Inst::BinaryExpr(crate::ExprKind::Sub), Inst::Call(0), Inst::Return,
+            Inst::Label(1),
+            Inst::Return,
+        ];
+
+        assert_eq!(verify(&recursive, &[]), Err(vec![VerifyError::StackUnderflow { ip: 3 }]));
+    }
+
+    #[test]
+    fn unknown_syscall_numbers() {
+        let syscall = |number: Inst| vec![push(0), push(0), push(0), number, Inst::Syscall, Inst::StackOp(StackOp::Dump), Inst::Return];
+
+        assert_eq!(
+            verify(&syscall(push(9)), &[]),
+            Err(vec![VerifyError::UnknownSyscall { ip: 4, number: 9 }]),
+        );
+
+        // The number is not known, the code after the syscall is still walked.
+        assert_eq!(
+            verify(&syscall(Inst::LocalOp(LocalOp::Load(0))), &[]),
+            Err(vec![VerifyError::ReturnOutsideCall { ip: 6 }]),
+        );
+    }
+
+    #[test]
+    fn code_after_host_calls_is_checked() {
+        let call = vec![Inst::Call(0), Inst::StackOp(StackOp::Dump), Inst::Halt, Inst::Label(0), Inst::HostCall(0), Inst::StackOp(StackOp::Depth), Inst::Return];
+
+        assert_eq!(verify(&call, &[function(0, 0, 1)]), Ok(()));
+        assert_eq!(verify(&call, &[function(0, 0, 0)]), Err(vec![VerifyError::StackUnderflow { ip: 1 }]));
+
+        assert_eq!(
+            verify(&[Inst::HostCall(0), Inst::Return], &[]),
+            Err(vec![VerifyError::ReturnOutsideCall { ip: 1 }]),
+        );
+        assert_eq!(
+            verify(&[Inst::HostCall(0), push(9), Inst::Syscall, Inst::Halt], &[]),
+            Err(vec![VerifyError::UnknownSyscall { ip: 2, number: 9 }]),
+        );
+    }
+}